@@ -0,0 +1,123 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use geoengine_datatypes::primitives::TimeInterval;
+use geoengine_datatypes::raster::{Grid2D, RasterTile2D, TileInformation};
+use ndarray::Array3;
+use numpy::PyArray3;
+use pyo3::types::PyDict;
+use pyo3::Python;
+use pythonic_experiments::convert::{py_to_grid_data, tiles_into_py, PyOutputBuffer};
+
+const TILE_SIZE: usize = 600;
+
+fn tile() -> RasterTile2D<f32> {
+    RasterTile2D::new_with_tile_info(
+        TimeInterval::default(),
+        TileInformation {
+            global_geo_transform: Default::default(),
+            global_tile_position: [0, 0].into(),
+            tile_size_in_pixels: [TILE_SIZE, TILE_SIZE].into(),
+        },
+        Grid2D::new(
+            [TILE_SIZE, TILE_SIZE].into(),
+            (0..TILE_SIZE * TILE_SIZE).map(|i| i as f32).collect(),
+            Some(-1.),
+        )
+        .unwrap(),
+    )
+}
+
+/// The former input hand-off, which cloned the tile data into an `Array3`
+fn copying_input(py: Python, tiles: &[RasterTile2D<f32>]) {
+    let data = tiles
+        .iter()
+        .flat_map(|tile| tile.grid_array.data.clone())
+        .collect();
+    let mask = vec![false; tiles.len() * TILE_SIZE * TILE_SIZE];
+
+    let data = Array3::from_shape_vec((tiles.len(), TILE_SIZE, TILE_SIZE), data).unwrap();
+    let mask = Array3::from_shape_vec((tiles.len(), TILE_SIZE, TILE_SIZE), mask).unwrap();
+
+    let kwargs = PyDict::new(py);
+    kwargs
+        .set_item("mask", PyArray3::from_owned_array(py, mask))
+        .unwrap();
+    py.import("numpy.ma")
+        .unwrap()
+        .call(
+            "masked_array",
+            (PyArray3::from_owned_array(py, data),),
+            Some(kwargs),
+        )
+        .unwrap();
+}
+
+fn input_hand_off(c: &mut Criterion) {
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+    let tile = tile();
+
+    c.bench_function("copying input hand-off", |b| {
+        b.iter(|| {
+            // SAFETY: the Python objects of an iteration do not outlive its pool
+            let pool = unsafe { py.new_pool() };
+            copying_input(pool.python(), std::slice::from_ref(&tile));
+        })
+    });
+
+    // the tiles are moved into numpy, so every iteration gets a fresh one, which is cloned
+    // before the timing starts, as the input stream of a query provides it
+    c.bench_function("zero-copy input hand-off", |b| {
+        b.iter_batched(
+            || vec![tile.clone()],
+            |mut tiles| {
+                // SAFETY: the Python objects of an iteration do not outlive its pool
+                let pool = unsafe { py.new_pool() };
+                tiles_into_py(pool.python(), &mut tiles).unwrap();
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+/// The output of a script that returns a new array, which is copied into the new grid
+fn copying_output(py: Python) -> Vec<f32> {
+    let result = py
+        .import("numpy")
+        .unwrap()
+        .call1("full", ((TILE_SIZE, TILE_SIZE), 1.0, "float32"))
+        .unwrap();
+
+    py_to_grid_data(result, [TILE_SIZE, TILE_SIZE].into(), Some(-1.)).unwrap()
+}
+
+/// The output of a script that fills the preallocated `out` array, which becomes the new grid
+fn preallocated_output(py: Python) -> Vec<f32> {
+    let buffer = PyOutputBuffer::<f32>::new(py, [TILE_SIZE, TILE_SIZE].into()).unwrap();
+    buffer.array(py).call_method1("fill", (1.0,)).unwrap();
+
+    buffer.into_grid_data(py, Some(-1.)).unwrap()
+}
+
+fn output_hand_off(c: &mut Criterion) {
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+
+    c.bench_function("copying output hand-off", |b| {
+        b.iter(|| {
+            // SAFETY: the Python objects of an iteration do not outlive its pool
+            let pool = unsafe { py.new_pool() };
+            copying_output(pool.python())
+        })
+    });
+
+    c.bench_function("preallocated output hand-off", |b| {
+        b.iter(|| {
+            // SAFETY: the Python objects of an iteration do not outlive its pool
+            let pool = unsafe { py.new_pool() };
+            preallocated_output(pool.python())
+        })
+    });
+}
+
+criterion_group!(benches, input_hand_off, output_hand_off);
+criterion_main!(benches);
@@ -26,11 +26,22 @@ use geoengine_operators::source::{
     GdalSourceParameters,
 };
 use geoengine_services::error::Result;
-use pythonic_experiments::example_pyop::{PyOperator, PyOperatorParams};
+use pythonic_experiments::example_pyop::{PyOperator, PyOperatorOptions, PyOperatorParams};
+use pythonic_experiments::interpreter::initialize;
+use pythonic_experiments::script::{PyHooks, PyScript};
+use pythonic_experiments::state::PyStateScope;
 use std::{convert::TryInto, fs::File, io::Write};
 
 #[tokio::main]
 async fn main() {
+    // 0. prepare the Python environment, so missing packages and broken plugins are reported
+    //    right away, and watch the scripts if `python.hot_reload` is enabled
+
+    if let Err(error) = initialize() {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+
     // 1. register source dataset
 
     let mut execution_context = MockExecutionContext::default();
@@ -40,7 +51,29 @@ async fn main() {
     // 2. define your workflow
 
     let operator = PyOperator {
-        params: PyOperatorParams { n_comp: 5. },
+        params: PyOperatorParams {
+            script: PyScript::Path("ipca.py".into()),
+            module_name: "ipca".to_string(),
+            hooks: PyHooks {
+                tile: "apply_ipca".to_string(),
+                fit: Some("partial_fit_ipca".to_string()),
+                setup: Some("setup".to_string()),
+                teardown: None,
+            },
+            options: PyOperatorOptions {
+                parameters: serde_json::json!({ "n_components": 5 }),
+                state: PyStateScope::Query,
+                model: None,
+                timeout_ms: None,
+                batch_size: None,
+                backend: None,
+                limits: Default::default(),
+                fit_buffer_bytes: None,
+                output_data_type: None,
+                output_measurement: None,
+                output_no_data_value: None,
+            },
+        },
         raster_sources: vec![GdalSource {
             params: GdalSourceParameters {
                 data_set: dataset_id,
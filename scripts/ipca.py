@@ -0,0 +1,45 @@
+from sklearn.decomposition import IncrementalPCA
+import numpy as np
+
+OPERATOR = {
+    'name': 'ipca',
+    'description': 'Reconstructs the tiles from their principal components',
+    'hooks': {'tile': 'apply_ipca', 'fit': 'partial_fit_ipca', 'setup': 'setup'},
+    'parameters': {'n_components': {'type': 'int', 'min': 1, 'default': 500}},
+}
+
+
+def setup(state, n_components=500, **kwargs):
+    state.ipca = IncrementalPCA(n_components=n_components)
+
+
+def run_pca(state, data):
+    state.ipca.partial_fit(data)
+    tmp = state.ipca.transform(data)
+    temp = state.ipca.inverse_transform(tmp)
+
+    return temp
+
+
+def partial_fit_ipca(tile, state, **kwargs):
+
+    print("fitting")
+
+    # only fit on rows without no-data pixels
+    band = tile[0]
+    complete_rows = ~np.ma.getmaskarray(band).any(axis=1)
+    samples = band.data[complete_rows]
+
+    if len(samples) >= state.ipca.n_components:
+        state.ipca.partial_fit(samples)
+
+
+def apply_ipca(tile, state, **kwargs):
+
+    print("transforming")
+
+    band = tile[0]
+    tmp = state.ipca.transform(band.filled(0))
+    temp = state.ipca.inverse_transform(tmp).astype(np.uint8)
+
+    return np.ma.masked_array(temp, mask=np.ma.getmaskarray(band))
@@ -0,0 +1,44 @@
+use crate::subprocess::PyBackend;
+use geoengine_services::util::config::ConfigElement;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Settings for the embedded Python interpreter, read from the `[python]` section
+#[derive(Debug, Clone, Deserialize)]
+pub struct Python {
+    /// Directory against which the script paths of Python operators are resolved, it is also on
+    /// the `sys.path`, so scripts can import the modules next to them
+    pub script_root: PathBuf,
+    /// Directory that is scanned for plugin scripts, which declare an operator
+    pub plugin_root: PathBuf,
+    /// Directory in which the fitted models of Python operators are stored
+    pub model_root: PathBuf,
+    /// Number of threads on which the Python code of all operators runs
+    pub worker_threads: usize,
+    /// Where Python operators run unless they choose a backend themselves
+    pub backend: PyBackend,
+    /// The interpreter that is started for the `subprocess` backend
+    pub python_executable: PathBuf,
+    /// Number of idle worker processes that are kept for later queries
+    pub subprocess_workers: usize,
+    /// How long a call of a fit or tile hook may take before it is interrupted, unless an
+    /// operator sets its own `timeout_ms`
+    pub timeout_ms: Option<u64>,
+    /// A virtualenv whose packages the embedded interpreter uses before the global ones
+    pub virtualenv: Option<PathBuf>,
+    /// Directories that are put in front of the embedded interpreter's `sys.path`
+    #[serde(default)]
+    pub extra_sys_path: Vec<PathBuf>,
+    /// Modules that are imported when the embedded interpreter is prepared, i.e., the packages
+    /// that the scripts require
+    #[serde(default)]
+    pub preload_modules: Vec<String>,
+    /// Whether changed scripts are loaded again without a restart, see `crate::reload`, which is
+    /// meant for developing scripts
+    #[serde(default)]
+    pub hot_reload: bool,
+}
+
+impl ConfigElement for Python {
+    const KEY: &'static str = "python";
+}
@@ -0,0 +1,960 @@
+use crate::error::{Error, Result};
+use geoengine_datatypes::collections::{
+    FeatureCollection, FeatureCollectionRow, TypedFeatureCollection,
+};
+use geoengine_datatypes::primitives::{
+    Coordinate2D, FeatureData, FeatureDataType, FeatureDataValue, Geometry, MultiLineString,
+    MultiLineStringRef, MultiPoint, MultiPointRef, MultiPolygon, MultiPolygonRef, NoGeometry,
+    TimeInterval,
+};
+use geoengine_datatypes::raster::{GridShape2D, Pixel, RasterTile2D};
+use geoengine_datatypes::util::arrow::ArrowTyped;
+use numpy::{npyffi, Element, PyArray1, PyArray2};
+use pyo3::prelude::pyclass;
+use pyo3::types::{IntoPyDict, PyAny, PyDict, PyList};
+use pyo3::{
+    AsPyPointer, FromPyObject, IntoPy, IntoPyPointer, Py, PyErr, PyObject, PyResult, Python,
+    ToPyObject,
+};
+use serde_json::Value;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::os::raw::c_int;
+
+/// Convert a JSON value into the equivalent Python object
+pub fn json_to_py(py: Python, value: &Value) -> PyResult<PyObject> {
+    Ok(match value {
+        Value::Null => py.None(),
+        Value::Bool(b) => b.into_py(py),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                i.into_py(py)
+            } else if let Some(u) = n.as_u64() {
+                u.into_py(py)
+            } else {
+                n.as_f64().unwrap_or(f64::NAN).into_py(py)
+            }
+        }
+        Value::String(s) => s.into_py(py),
+        Value::Array(values) => {
+            let list = PyList::empty(py);
+            for value in values {
+                list.append(json_to_py(py, value)?)?;
+            }
+            list.to_object(py)
+        }
+        Value::Object(map) => {
+            let dict = PyDict::new(py);
+            for (key, value) in map {
+                dict.set_item(key, json_to_py(py, value)?)?;
+            }
+            dict.to_object(py)
+        }
+    })
+}
+
+/// Convert a JSON object of operator parameters into Python keyword arguments
+pub fn json_to_kwargs<'py>(py: Python<'py>, parameters: &Value) -> PyResult<&'py PyDict> {
+    let kwargs = PyDict::new(py);
+    if let Value::Object(map) = parameters {
+        for (key, value) in map {
+            kwargs.set_item(key, json_to_py(py, value)?)?;
+        }
+    }
+    Ok(kwargs)
+}
+
+/// Check that `parameters` can be passed as keyword arguments, i.e., it is an object or null
+pub fn check_kwargs(parameters: &Value) -> Result<()> {
+    match parameters {
+        Value::Null | Value::Object(_) => Ok(()),
+        _ => Err(Error::InvalidPythonParameters {
+            found: parameters.to_string(),
+        }),
+    }
+}
+
+/// Hand the grids of aligned tiles to numpy as a read-only masked array of shape
+/// `(bands, rows, columns)`
+///
+/// The pixel data is moved out of the tiles instead of being copied, so afterwards the tiles only
+/// carry their metadata. The buffer of a single tile becomes the memory of the array as is,
+/// several tiles are stacked into one buffer. Pixels that equal the no-data value of their tile
+/// are masked.
+pub fn tiles_into_py<'py, T>(py: Python<'py>, tiles: &mut [RasterTile2D<T>]) -> PyResult<&'py PyAny>
+where
+    T: Element + Pixel,
+{
+    let [rows, columns] = tiles[0].grid_array.shape.shape_array;
+    let shape = [tiles.len(), rows, columns];
+
+    let ma = py.import("numpy.ma")?;
+
+    let mask = if tiles
+        .iter()
+        .any(|tile| tile.grid_array.no_data_value.is_some())
+    {
+        let mask = tiles
+            .iter()
+            .flat_map(|tile| {
+                let no_data_value = tile.grid_array.no_data_value;
+                tile.grid_array.data.iter().map(move |&value| {
+                    no_data_value.map_or(false, |no_data| is_no_data(value, no_data))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        PyArray1::from_vec(py, mask).reshape(shape)?.as_ref()
+    } else {
+        ma.getattr("nomask")?
+    };
+
+    let data = if tiles.len() == 1 {
+        std::mem::take(&mut tiles[0].grid_array.data)
+    } else {
+        let mut data = Vec::with_capacity(tiles.len() * rows * columns);
+        for tile in tiles.iter_mut() {
+            data.append(&mut tile.grid_array.data);
+        }
+        data
+    };
+
+    let data = PyArray1::from_vec(py, data).reshape(shape)?;
+    data.call_method("setflags", (), Some([("write", false)].into_py_dict(py)))?;
+
+    let kwargs = PyDict::new(py);
+    kwargs.set_item("mask", mask)?;
+
+    ma.call("masked_array", (data,), Some(kwargs))
+}
+
+/// Whether `value` is the no-data value, where a NaN no-data value matches all NaNs
+fn is_no_data<T: Pixel>(value: T, no_data_value: T) -> bool {
+    let (value_f64, no_data_f64): (f64, f64) = (value.as_(), no_data_value.as_());
+
+    value == no_data_value || (value_f64.is_nan() && no_data_f64.is_nan())
+}
+
+/// Extract the data of a numpy array that was returned for a grid of shape `shape`
+///
+/// The data is copied once, straight into the buffer of the new grid, where masked elements and
+/// NaNs are replaced by `no_data_value`. A result that was written into a `PyOutputBuffer` needs
+/// no copy. Without a no-data value, masked elements and NaNs are an error, since the new grid
+/// could not mark them.
+pub fn py_to_grid_data<T>(
+    array: &PyAny,
+    shape: GridShape2D,
+    no_data_value: Option<T>,
+) -> Result<Vec<T>>
+where
+    T: Element + Pixel,
+{
+    let (data, mask) = split_mask(array).map_err(|_| Error::InvalidPythonOutput {
+        expected: "a numpy array or masked array".to_string(),
+        found: array.get_type().to_string(),
+    })?;
+
+    let data = data
+        .downcast::<PyArray2<T>>()
+        .map_err(|_| Error::InvalidPythonOutput {
+            expected: format!("a 2D numpy array of {}", std::any::type_name::<T>()),
+            found: data.get_type().to_string(),
+        })?;
+
+    if data.shape() != shape.shape_array {
+        return Err(Error::InvalidPythonOutput {
+            expected: format!("shape {:?}", shape.shape_array),
+            found: format!("shape {:?}", data.shape()),
+        });
+    }
+
+    let data = data.readonly();
+    let mut grid_data = match data.as_slice() {
+        Ok(contiguous) => contiguous.to_vec(),
+        Err(_) => data.as_array().iter().copied().collect(),
+    };
+
+    if let Some(mask) = mask {
+        let mask = mask.readonly();
+        let mask = mask.as_array();
+
+        match no_data_value {
+            Some(no_data_value) => {
+                for (value, &masked) in grid_data.iter_mut().zip(mask.iter()) {
+                    if masked {
+                        *value = no_data_value;
+                    }
+                }
+            }
+            None => {
+                let masked = mask.iter().filter(|&&masked| masked).count();
+                if masked > 0 {
+                    return Err(missing_no_data_value(format!("{} masked elements", masked)));
+                }
+            }
+        }
+    }
+
+    replace_nans(&mut grid_data, no_data_value)?;
+
+    Ok(grid_data)
+}
+
+/// Replace the NaNs in `data` by `no_data_value`, they are an error without one
+fn replace_nans<T: Pixel>(data: &mut [T], no_data_value: Option<T>) -> Result<()> {
+    let is_nan = |value: &T| {
+        let value: f64 = value.as_();
+        value.is_nan()
+    };
+
+    match no_data_value {
+        Some(no_data_value) => {
+            for value in data.iter_mut().filter(|value| is_nan(value)) {
+                *value = no_data_value;
+            }
+        }
+        None => {
+            let nans = data.iter().filter(|value| is_nan(value)).count();
+            if nans > 0 {
+                return Err(missing_no_data_value(format!("{} NaN elements", nans)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The error for an output with no-data elements, `found`, but without a no-data value
+pub fn missing_no_data_value(found: String) -> Error {
+    Error::InvalidPythonOutput {
+        expected: "no masked or NaN elements, since the output has no no-data value".to_string(),
+        found,
+    }
+}
+
+/// Owns the pixels that the array of a `PyOutputBuffer` refers to
+#[pyclass]
+struct PixelBuffer {
+    pixels: Option<Box<dyn Any + Send>>,
+}
+
+/// A preallocated grid buffer, which the tile hook gets as a writable numpy array to write its
+/// result into
+///
+/// The array refers to the pixels without copying them, and so do the new grids that are taken
+/// from it.
+pub struct PyOutputBuffer<T> {
+    array: Py<PyArray2<T>>,
+    owner: Py<PixelBuffer>,
+}
+
+impl<T> PyOutputBuffer<T>
+where
+    T: Element + Pixel,
+{
+    /// A zeroed buffer for a grid of shape `shape`
+    pub fn new(py: Python, shape: GridShape2D) -> PyResult<Self> {
+        let [rows, columns] = shape.shape_array;
+        let mut pixels = zeroed_pixels::<T>(rows * columns);
+        let data = pixels.as_mut_ptr();
+
+        let owner = Py::new(
+            py,
+            PixelBuffer {
+                pixels: Some(Box::new(pixels)),
+            },
+        )?;
+
+        let mut dims = [rows as npyffi::npy_intp, columns as npyffi::npy_intp];
+
+        // SAFETY: the pixels do not move when their vector is boxed, and the array keeps them
+        // alive by holding their owner as its base, which steals the passed reference
+        let array = unsafe {
+            let array = npyffi::PY_ARRAY_API.PyArray_New(
+                npyffi::PY_ARRAY_API.get_type_object(npyffi::NpyTypes::PyArray_Type),
+                2,
+                dims.as_mut_ptr(),
+                T::npy_type() as c_int,
+                std::ptr::null_mut(),
+                data.cast(),
+                std::mem::size_of::<T>() as c_int,
+                npyffi::NPY_ARRAY_WRITEABLE,
+                std::ptr::null_mut(),
+            );
+
+            if array.is_null() {
+                return Err(PyErr::fetch(py));
+            }
+
+            npyffi::PY_ARRAY_API
+                .PyArray_SetBaseObject(array.cast(), owner.clone_ref(py).into_ptr());
+
+            Py::from_owned_ptr(py, array)
+        };
+
+        Ok(Self { array, owner })
+    }
+
+    /// The writable numpy array of the buffer
+    pub fn array<'py>(&self, py: Python<'py>) -> &'py PyArray2<T> {
+        self.array.as_ref(py)
+    }
+
+    /// Whether `result` is the array of the buffer, i.e., the hook wrote into it
+    pub fn is_result(&self, result: &PyAny) -> bool {
+        result.as_ptr() == self.array.as_ptr()
+    }
+
+    /// The pixels of the new grid, where NaNs are replaced by `no_data_value`, see
+    /// `py_to_grid_data`
+    ///
+    /// They are only copied if Python still refers to the array, e.g., because a script stored it
+    /// in its state. Thus, the references to the array, like the keyword arguments of the call,
+    /// should be released before.
+    pub fn into_grid_data(self, py: Python, no_data_value: Option<T>) -> Result<Vec<T>> {
+        let Self { array, owner } = self;
+
+        // the GIL is held, so this releases the array right away if nothing else refers to it
+        drop(array);
+
+        let mut buffer = owner.as_ref(py).borrow_mut();
+        let taken = if owner.get_refcnt(py) == 1 {
+            buffer.pixels.take()
+        } else {
+            None
+        };
+
+        let mut pixels = match taken {
+            Some(pixels) => *pixels
+                .downcast::<Vec<T>>()
+                .expect("the buffer owns pixels of type T"),
+            // Python still refers to the pixels, so they stay with their owner
+            None => buffer
+                .pixels
+                .as_ref()
+                .and_then(|pixels| pixels.downcast_ref::<Vec<T>>())
+                .expect("the buffer owns pixels of type T")
+                .clone(),
+        };
+
+        replace_nans(&mut pixels, no_data_value)?;
+
+        Ok(pixels)
+    }
+}
+
+/// A vector of `len` pixels that are all zero
+pub fn zeroed_pixels<T: Pixel>(len: usize) -> Vec<T> {
+    let mut pixels = Vec::<T>::with_capacity(len);
+
+    // SAFETY: the vector has room for `len` pixels, and a pixel of zeroed bytes is a valid zero
+    unsafe {
+        std::ptr::write_bytes(pixels.as_mut_ptr(), 0, len);
+        pixels.set_len(len);
+    }
+
+    pixels
+}
+
+/// Split a masked array into a view of its data and its mask, if any element is masked
+fn split_mask(array: &PyAny) -> PyResult<(&PyAny, Option<&PyArray2<bool>>)> {
+    let ma = array.py().import("numpy.ma")?;
+
+    if !ma.call1("isMaskedArray", (array,))?.is_true()? {
+        return Ok((array, None));
+    }
+
+    // an array without masked elements has the scalar `nomask` as its mask
+    let mask = ma
+        .call1("getmask", (array,))?
+        .downcast::<PyArray2<bool>>()
+        .ok();
+
+    Ok((ma.call1("getdata", (array,))?, mask))
+}
+
+/// Convert the feature collection chunks of one vector input into a Python dict with the keys
+/// `type`, `geometries`, `columns` and `time`
+pub fn feature_collections_to_py(
+    py: Python,
+    collections: &[TypedFeatureCollection],
+) -> PyResult<PyObject> {
+    let mut features = FeaturesBuilder::default();
+
+    for collection in collections {
+        match collection {
+            TypedFeatureCollection::Data(c) => features.push_rows(py, "Data", c.into_iter()),
+            TypedFeatureCollection::MultiPoint(c) => {
+                features.push_rows(py, "MultiPoint", c.into_iter())
+            }
+            TypedFeatureCollection::MultiLineString(c) => {
+                features.push_rows(py, "MultiLineString", c.into_iter())
+            }
+            TypedFeatureCollection::MultiPolygon(c) => {
+                features.push_rows(py, "MultiPolygon", c.into_iter())
+            }
+        }
+    }
+
+    features.finish(py)
+}
+
+#[derive(Default)]
+struct FeaturesBuilder {
+    type_name: Option<&'static str>,
+    geometries: Vec<PyObject>,
+    time: Vec<PyObject>,
+    columns: BTreeMap<String, Vec<PyObject>>,
+}
+
+impl FeaturesBuilder {
+    fn push_rows<'c, G>(
+        &mut self,
+        py: Python,
+        type_name: &'static str,
+        rows: impl Iterator<Item = FeatureCollectionRow<'c, G>>,
+    ) where
+        G: GeometryToPy,
+    {
+        self.type_name = Some(type_name);
+
+        for row in rows {
+            self.geometries.push(row.geometry.to_py(py));
+            self.time.push(time_interval_to_py(py, &row.time_interval));
+
+            for (column, value) in row.data {
+                self.columns
+                    .entry(column.to_string())
+                    .or_default()
+                    .push(feature_data_value_to_py(py, value));
+            }
+        }
+    }
+
+    fn finish(self, py: Python) -> PyResult<PyObject> {
+        let columns = PyDict::new(py);
+        for (column, values) in self.columns {
+            columns.set_item(column, values)?;
+        }
+
+        let features = PyDict::new(py);
+        features.set_item("type", self.type_name.unwrap_or("Data"))?;
+        features.set_item("geometries", self.geometries)?;
+        features.set_item("columns", columns)?;
+        features.set_item("time", self.time)?;
+
+        Ok(features.to_object(py))
+    }
+}
+
+/// Geometries that are handed to Python as (nested) lists of `(x, y)` tuples
+trait GeometryToPy {
+    fn to_py(&self, py: Python) -> PyObject;
+}
+
+fn coordinates_to_py(py: Python, coordinates: &[Coordinate2D]) -> PyObject {
+    coordinates
+        .iter()
+        .map(|c| (c.x, c.y))
+        .collect::<Vec<_>>()
+        .into_py(py)
+}
+
+impl GeometryToPy for NoGeometry {
+    fn to_py(&self, py: Python) -> PyObject {
+        py.None()
+    }
+}
+
+impl<'g> GeometryToPy for MultiPointRef<'g> {
+    fn to_py(&self, py: Python) -> PyObject {
+        coordinates_to_py(py, self.points())
+    }
+}
+
+impl<'g> GeometryToPy for MultiLineStringRef<'g> {
+    fn to_py(&self, py: Python) -> PyObject {
+        self.lines()
+            .iter()
+            .map(|line| coordinates_to_py(py, line))
+            .collect::<Vec<_>>()
+            .into_py(py)
+    }
+}
+
+impl<'g> GeometryToPy for MultiPolygonRef<'g> {
+    fn to_py(&self, py: Python) -> PyObject {
+        self.polygons()
+            .iter()
+            .map(|polygon| {
+                polygon
+                    .iter()
+                    .map(|ring| coordinates_to_py(py, ring))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+            .into_py(py)
+    }
+}
+
+/// Convert a time interval into a `(start, end)` tuple of milliseconds since the epoch
+pub fn time_interval_to_py(py: Python, time_interval: &TimeInterval) -> PyObject {
+    (time_interval.start().inner(), time_interval.end().inner()).into_py(py)
+}
+
+fn feature_data_value_to_py(py: Python, value: FeatureDataValue) -> PyObject {
+    match value {
+        FeatureDataValue::Categorical(v) => v.into_py(py),
+        FeatureDataValue::NullableCategorical(v) => v.into_py(py),
+        FeatureDataValue::Decimal(v) => v.into_py(py),
+        FeatureDataValue::NullableDecimal(v) => v.into_py(py),
+        FeatureDataValue::Number(v) => v.into_py(py),
+        FeatureDataValue::NullableNumber(v) => v.into_py(py),
+        FeatureDataValue::Text(v) => v.into_py(py),
+        FeatureDataValue::NullableText(v) => v.into_py(py),
+    }
+}
+
+/// Feature collections that can be built from the dict a Python script returns, which has the
+/// same layout as the dicts created by `feature_collections_to_py`
+pub trait FeaturesFromPy: Sized {
+    /// Build the collection with the `columns` declared by the operator, using `default_time`
+    /// for features without a `time` entry
+    fn from_py(
+        features: &PyAny,
+        columns: &HashMap<String, FeatureDataType>,
+        default_time: TimeInterval,
+    ) -> Result<Self>;
+}
+
+impl<G> FeaturesFromPy for FeatureCollection<G>
+where
+    G: Geometry + ArrowTyped + GeometryFromPy,
+{
+    fn from_py(
+        features: &PyAny,
+        columns: &HashMap<String, FeatureDataType>,
+        default_time: TimeInterval,
+    ) -> Result<Self> {
+        let geometries = item_of_output(features, "geometries")?
+            .iter()
+            .map_err(|_| Error::InvalidPythonOutput {
+                expected: "a list of geometries".to_string(),
+                found: features.get_type().to_string(),
+            })?
+            .map(|geometry| {
+                geometry
+                    .map_err(|_| invalid_geometry())
+                    .and_then(G::from_py)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let time_intervals = match features.get_item("time") {
+            Ok(time) if !time.is_none() => {
+                extract_output::<Vec<(i64, i64)>>(time, "a list of (start, end) tuples")?
+                    .into_iter()
+                    .map(|(start, end)| TimeInterval::new(start, end))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            _ => vec![default_time; geometries.len()],
+        };
+
+        let py_columns = item_of_output(features, "columns")?;
+        let mut data = HashMap::with_capacity(columns.len());
+        for (column, data_type) in columns {
+            let values =
+                py_columns
+                    .get_item(column.as_str())
+                    .map_err(|_| Error::InvalidPythonOutput {
+                        expected: format!("a column \"{}\"", column),
+                        found: "no such column".to_string(),
+                    })?;
+
+            data.insert(column.clone(), py_to_feature_data(values, *data_type)?);
+        }
+
+        Ok(Self::from_data(geometries, time_intervals, data)?)
+    }
+}
+
+fn item_of_output<'py>(features: &'py PyAny, key: &str) -> Result<&'py PyAny> {
+    features
+        .get_item(key)
+        .map_err(|_| Error::InvalidPythonOutput {
+            expected: format!("a dict with the key \"{}\"", key),
+            found: features.get_type().to_string(),
+        })
+}
+
+fn extract_output<'py, T>(value: &'py PyAny, expected: &str) -> Result<T>
+where
+    T: FromPyObject<'py>,
+{
+    value.extract().map_err(|_| Error::InvalidPythonOutput {
+        expected: expected.to_string(),
+        found: value.get_type().to_string(),
+    })
+}
+
+fn py_to_feature_data(values: &PyAny, data_type: FeatureDataType) -> Result<FeatureData> {
+    let expected = format!("a list of {:?} values", data_type);
+
+    Ok(match data_type {
+        FeatureDataType::Categorical => {
+            FeatureData::Categorical(extract_output(values, &expected)?)
+        }
+        FeatureDataType::NullableCategorical => {
+            FeatureData::NullableCategorical(extract_output(values, &expected)?)
+        }
+        FeatureDataType::Decimal => FeatureData::Decimal(extract_output(values, &expected)?),
+        FeatureDataType::NullableDecimal => {
+            FeatureData::NullableDecimal(extract_output(values, &expected)?)
+        }
+        FeatureDataType::Number => FeatureData::Number(extract_output(values, &expected)?),
+        FeatureDataType::NullableNumber => {
+            FeatureData::NullableNumber(extract_output(values, &expected)?)
+        }
+        FeatureDataType::Text => FeatureData::Text(extract_output(values, &expected)?),
+        FeatureDataType::NullableText => {
+            FeatureData::NullableText(extract_output(values, &expected)?)
+        }
+    })
+}
+
+/// Geometries that are read from the (nested) lists of `(x, y)` tuples returned by Python
+pub trait GeometryFromPy: Sized {
+    fn from_py(geometry: &PyAny) -> Result<Self>;
+}
+
+fn invalid_geometry() -> Error {
+    Error::InvalidPythonOutput {
+        expected: "geometries as (nested) lists of (x, y) tuples".to_string(),
+        found: "an invalid geometry".to_string(),
+    }
+}
+
+fn extract_coordinates<T>(geometry: &PyAny) -> Result<T>
+where
+    for<'py> T: FromPyObject<'py>,
+{
+    geometry.extract().map_err(|_| invalid_geometry())
+}
+
+impl GeometryFromPy for NoGeometry {
+    fn from_py(_geometry: &PyAny) -> Result<Self> {
+        Ok(NoGeometry)
+    }
+}
+
+impl GeometryFromPy for MultiPoint {
+    fn from_py(geometry: &PyAny) -> Result<Self> {
+        let points: Vec<(f64, f64)> = extract_coordinates(geometry)?;
+
+        Ok(MultiPoint::new(
+            points.into_iter().map(Into::into).collect(),
+        )?)
+    }
+}
+
+impl GeometryFromPy for MultiLineString {
+    fn from_py(geometry: &PyAny) -> Result<Self> {
+        let lines: Vec<Vec<(f64, f64)>> = extract_coordinates(geometry)?;
+
+        Ok(MultiLineString::new(
+            lines
+                .into_iter()
+                .map(|line| line.into_iter().map(Into::into).collect())
+                .collect(),
+        )?)
+    }
+}
+
+impl GeometryFromPy for MultiPolygon {
+    fn from_py(geometry: &PyAny) -> Result<Self> {
+        let polygons: Vec<Vec<Vec<(f64, f64)>>> = extract_coordinates(geometry)?;
+
+        Ok(MultiPolygon::new(
+            polygons
+                .into_iter()
+                .map(|polygon| {
+                    polygon
+                        .into_iter()
+                        .map(|ring| ring.into_iter().map(Into::into).collect())
+                        .collect()
+                })
+                .collect(),
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geoengine_datatypes::collections::{MultiPointCollection, MultiPolygonCollection};
+    use geoengine_datatypes::raster::{Grid2D, TileInformation};
+    use serde_json::json;
+
+    fn tile(shape: [usize; 2], data: Vec<u8>) -> RasterTile2D<u8> {
+        RasterTile2D::new_with_tile_info(
+            TimeInterval::default(),
+            TileInformation {
+                global_geo_transform: Default::default(),
+                global_tile_position: [0, 0].into(),
+                tile_size_in_pixels: shape.into(),
+            },
+            Grid2D::new(shape.into(), data, None).unwrap(),
+        )
+    }
+
+    #[test]
+    fn converts_parameters_to_kwargs() {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let kwargs = json_to_kwargs(
+            py,
+            &json!({ "n_components": 4, "whiten": true, "labels": ["a", "b"] }),
+        )
+        .unwrap();
+
+        assert_eq!(
+            kwargs
+                .get_item("n_components")
+                .unwrap()
+                .extract::<i64>()
+                .unwrap(),
+            4
+        );
+        assert!(kwargs
+            .get_item("whiten")
+            .unwrap()
+            .extract::<bool>()
+            .unwrap());
+        assert_eq!(
+            kwargs
+                .get_item("labels")
+                .unwrap()
+                .extract::<Vec<String>>()
+                .unwrap(),
+            vec!["a".to_string(), "b".to_string()]
+        );
+    }
+
+    #[test]
+    fn converts_non_square_tiles() {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let mut tiles = vec![
+            tile([2, 3], vec![1_u8, 2, 3, 4, 5, 6]),
+            tile([2, 3], vec![7, 8, 9, 10, 11, 12]),
+        ];
+
+        let array = tiles_into_py(py, &mut tiles).unwrap();
+        assert_eq!(
+            array
+                .getattr("shape")
+                .unwrap()
+                .extract::<(usize, usize, usize)>()
+                .unwrap(),
+            (2, 2, 3)
+        );
+        assert_eq!(
+            array.get_item((1, 1, 0)).unwrap().extract::<u8>().unwrap(),
+            10
+        );
+
+        let band = array.get_item(0).unwrap();
+        let transposed = band.call_method0("transpose").unwrap();
+        assert!(py_to_grid_data::<u8>(transposed, tiles[0].grid_array.shape, None).is_err());
+
+        let data = py_to_grid_data::<u8>(band, tiles[0].grid_array.shape, None).unwrap();
+        assert_eq!(data, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn hands_tile_buffers_to_numpy() {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let data = vec![1_u8, 2, 3, 4];
+        let buffer = data.as_ptr() as usize;
+        let mut tiles = vec![tile([2, 2], data)];
+
+        let array = tiles_into_py(py, &mut tiles).unwrap();
+
+        // the array uses the tile's buffer, which is read-only to scripts
+        let address = array
+            .getattr("data")
+            .unwrap()
+            .getattr("ctypes")
+            .unwrap()
+            .getattr("data")
+            .unwrap()
+            .extract::<usize>()
+            .unwrap();
+        assert_eq!(address, buffer);
+        assert!(tiles[0].grid_array.data.is_empty());
+        assert!(array.set_item((0, 0, 0), 42).is_err());
+    }
+
+    #[test]
+    fn masks_no_data() {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let mut tiles = vec![RasterTile2D::new_with_tile_info(
+            TimeInterval::default(),
+            TileInformation {
+                global_geo_transform: Default::default(),
+                global_tile_position: [0, 0].into(),
+                tile_size_in_pixels: [2, 2].into(),
+            },
+            Grid2D::new([2, 2].into(), vec![0_u8, 2, 0, 4], Some(0)).unwrap(),
+        )];
+
+        let array = tiles_into_py(py, &mut tiles).unwrap();
+        assert_eq!(
+            array
+                .call_method0("count")
+                .unwrap()
+                .extract::<usize>()
+                .unwrap(),
+            2
+        );
+
+        let band = array.get_item(0).unwrap();
+        let data = py_to_grid_data::<u8>(band, [2, 2].into(), Some(255)).unwrap();
+        assert_eq!(data, vec![255, 2, 255, 4]);
+        // without a no-data value, the masked elements cannot be marked
+        assert!(py_to_grid_data::<u8>(band, [2, 2].into(), None).is_err());
+
+        let with_nan = py
+            .eval(
+                "np.array([[1., np.nan], [3., 4.]])",
+                None,
+                Some([("np", py.import("numpy").unwrap())].into_py_dict(py)),
+            )
+            .unwrap();
+        let data = py_to_grid_data::<f64>(with_nan, [2, 2].into(), Some(-1.)).unwrap();
+        assert_eq!(data, vec![1., -1., 3., 4.]);
+        assert!(py_to_grid_data::<f64>(with_nan, [2, 2].into(), None).is_err());
+    }
+
+    #[test]
+    fn rejects_non_object_parameters() {
+        assert!(check_kwargs(&json!({ "n_components": 4 })).is_ok());
+        assert!(check_kwargs(&serde_json::Value::Null).is_ok());
+        assert!(check_kwargs(&json!([4])).is_err());
+    }
+
+    #[test]
+    fn converts_feature_collections() {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let collection = MultiPointCollection::from_data(
+            MultiPoint::many(vec![(0.0, 0.1), (1.0, 1.1)]).unwrap(),
+            vec![TimeInterval::new_unchecked(0, 1); 2],
+            [(
+                "label".to_string(),
+                FeatureData::Text(vec!["a".to_string(), "b".to_string()]),
+            )]
+            .iter()
+            .cloned()
+            .collect(),
+        )
+        .unwrap();
+
+        let features =
+            feature_collections_to_py(py, &[TypedFeatureCollection::MultiPoint(collection)])
+                .unwrap();
+        let features = features.as_ref(py);
+
+        assert_eq!(
+            features
+                .get_item("type")
+                .unwrap()
+                .extract::<String>()
+                .unwrap(),
+            "MultiPoint"
+        );
+        assert_eq!(
+            features
+                .get_item("geometries")
+                .unwrap()
+                .extract::<Vec<Vec<(f64, f64)>>>()
+                .unwrap(),
+            vec![vec![(0.0, 0.1)], vec![(1.0, 1.1)]]
+        );
+        assert_eq!(
+            features
+                .get_item("columns")
+                .unwrap()
+                .get_item("label")
+                .unwrap()
+                .extract::<Vec<String>>()
+                .unwrap(),
+            vec!["a".to_string(), "b".to_string()]
+        );
+        assert_eq!(
+            features
+                .get_item("time")
+                .unwrap()
+                .extract::<Vec<(i64, i64)>>()
+                .unwrap(),
+            vec![(0, 1), (0, 1)]
+        );
+    }
+
+    #[test]
+    fn converts_features_from_py() {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let features = py
+            .eval(
+                "{'geometries': [[[(0., 0.), (1., 0.), (1., 1.), (0., 0.)]]], 'columns': {'area': [0.5]}}",
+                None,
+                None,
+            )
+            .unwrap();
+
+        let columns = [("area".to_string(), FeatureDataType::Number)]
+            .iter()
+            .cloned()
+            .collect();
+
+        let collection =
+            MultiPolygonCollection::from_py(features, &columns, TimeInterval::new_unchecked(0, 1))
+                .unwrap();
+
+        let expected = MultiPolygonCollection::from_data(
+            vec![MultiPolygon::new(vec![vec![vec![
+                (0., 0.).into(),
+                (1., 0.).into(),
+                (1., 1.).into(),
+                (0., 0.).into(),
+            ]]])
+            .unwrap()],
+            vec![TimeInterval::new_unchecked(0, 1)],
+            [("area".to_string(), FeatureData::Number(vec![0.5]))]
+                .iter()
+                .cloned()
+                .collect(),
+        )
+        .unwrap();
+
+        assert_eq!(collection, expected);
+
+        assert!(MultiPolygonCollection::from_py(
+            features,
+            &[("label".to_string(), FeatureDataType::Text)]
+                .iter()
+                .cloned()
+                .collect(),
+            TimeInterval::default(),
+        )
+        .is_err());
+    }
+}
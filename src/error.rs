@@ -1,12 +1,15 @@
+use crate::schema::PyParameterViolation;
+use pyo3::{PyErr, PyResult, Python};
 use snafu::Snafu;
 use std::ops::Range;
+use std::path::PathBuf;
+use std::time::Duration;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub(crate)")]
 pub enum Error {
-
     #[snafu(display("DatatypeError: {}", source))]
     Datatype {
         source: geoengine_datatypes::error::Error,
@@ -17,6 +20,11 @@ pub enum Error {
         source: geoengine_operators::error::Error,
     },
 
+    #[snafu(display("ServiceError: {}", source))]
+    Service {
+        source: geoengine_services::error::Error,
+    },
+
     #[snafu(display("InvalidNumberOfRasterInputsError: expected \"[{} .. {}]\" found \"{}\"", expected.start, expected.end, found))]
     InvalidNumberOfRasterInputs {
         expected: Range<usize>,
@@ -29,6 +37,197 @@ pub enum Error {
         found: usize,
     },
 
+    #[snafu(display("CannotReadScriptError: \"{}\": {}", path.display(), source))]
+    CannotReadScript {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("ScriptPathOutsideRootError: \"{}\" must be relative to the script root", path.display()))]
+    ScriptPathOutsideRoot { path: PathBuf },
+
+    #[snafu(display("CannotReadPluginsError: \"{}\": {}", path.display(), source))]
+    CannotReadPlugins {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("CannotWatchScriptsError: {}", source))]
+    CannotWatchScripts { source: notify::Error },
+
+    #[snafu(display("InvalidPythonPluginDeclarationError: {}", reason))]
+    InvalidPythonPluginDeclaration { reason: String },
+
+    #[snafu(display("InvalidPythonPluginsError: {}", plugins.join(", ")))]
+    InvalidPythonPlugins { plugins: Vec<String> },
+
+    #[snafu(display(
+        "UnknownPythonOperatorError: no plugin declares \"{}\", available are [{}]",
+        name,
+        available.join(", ")
+    ))]
+    UnknownPythonOperator {
+        name: String,
+        available: Vec<String>,
+    },
+
+    #[snafu(display(
+        "MissingPythonFunctionError: module \"{}\" has no callable \"{}\"",
+        module,
+        function
+    ))]
+    MissingPythonFunction { module: String, function: String },
+
+    #[snafu(display(
+        "MisplacedPythonHookError: \"{}\" of module \"{}\" is decorated as a {} hook",
+        function,
+        module,
+        kind
+    ))]
+    MisplacedPythonHook {
+        module: String,
+        function: String,
+        kind: String,
+    },
+
+    #[snafu(display(
+        "InvalidPythonParametersError: expected a JSON object, found \"{}\"",
+        found
+    ))]
+    InvalidPythonParameters { found: String },
+
+    #[snafu(display("InvalidParameterSchemaError: {}", reason))]
+    InvalidParameterSchema { reason: String },
+
+    #[snafu(display(
+        "PythonParametersSchemaError: {}",
+        violations
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))]
+    ParametersViolateSchema {
+        violations: Vec<PyParameterViolation>,
+    },
+
+    #[snafu(display("InvalidPythonOutputError: expected {}, found {}", expected, found))]
+    InvalidPythonOutput { expected: String, found: String },
+
+    #[snafu(display("IncompatibleRasterInputsError: {}", reason))]
+    IncompatibleRasterInputs { reason: String },
+
+    #[snafu(display("UnalignedRasterInputsError: {}", reason))]
+    UnalignedRasterInputs { reason: String },
+
+    #[snafu(display(
+        "InvalidModelIdError: \"{}\" must only contain letters, digits, '-' and '_'",
+        id
+    ))]
+    InvalidModelId { id: String },
+
+    #[snafu(display("CannotReadModelError: \"{}\": {}", path.display(), source))]
+    CannotReadModel {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("CannotWriteModelError: \"{}\": {}", path.display(), source))]
+    CannotWriteModel {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(
+        "PythonWorkerFailedError: the Python worker stopped before returning a result"
+    ))]
+    PythonWorkerFailed,
+
+    #[snafu(display(
+        "PythonTimeoutError: the Python call did not finish within {} ms",
+        timeout.as_millis()
+    ))]
+    PythonTimeout { timeout: Duration },
+
+    #[snafu(display(
+        "ResourceLimitExceededError: the Python script needed more than {}",
+        limit
+    ))]
+    ResourceLimitExceeded { limit: String },
+
+    #[snafu(display("CannotStartPythonWorkerError: \"{}\": {}", python.display(), source))]
+    CannotStartPythonWorker {
+        python: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("PythonWorkerCrashedError: the Python process exited with {}", status))]
+    PythonWorkerCrashed { status: String },
+
+    #[snafu(display("CannotExchangeTilesError: \"{}\": {}", path.display(), source))]
+    CannotExchangeTiles {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("UnsupportedByBackendError: {}", reason))]
+    UnsupportedByBackend { reason: String },
+
+    #[snafu(display(
+        "InvalidVirtualenvError: \"{}\" has no site-packages for Python {}",
+        path.display(),
+        python_version
+    ))]
+    InvalidVirtualenv {
+        path: PathBuf,
+        python_version: String,
+    },
+
+    #[snafu(display(
+        "MissingPythonModulesError: cannot import {}, install them into the configured virtualenv or the interpreter's environment",
+        modules.join(", ")
+    ))]
+    MissingPythonModules { modules: Vec<String> },
+
+    #[snafu(display("PythonError: {}: {}\n{}", exception_type, message, traceback))]
+    Python {
+        exception_type: String,
+        message: String,
+        traceback: String,
+    },
+}
+
+impl Error {
+    /// Capture the type, message and formatted traceback of a Python exception
+    pub fn from_py_err(py: Python, error: PyErr) -> Self {
+        let traceback = error
+            .ptraceback(py)
+            .and_then(|traceback| {
+                py.import("traceback")
+                    .and_then(|module| module.call1("format_tb", (traceback,)))
+                    .and_then(|lines| lines.extract::<Vec<String>>())
+                    .ok()
+            })
+            .map(|lines| lines.concat())
+            .unwrap_or_default();
+
+        Self::Python {
+            exception_type: error.ptype(py).name().to_string(),
+            message: error.pvalue(py).to_string(),
+            traceback,
+        }
+    }
+}
+
+/// Convert the exceptions of Python calls into `Error::Python`
+pub trait PyResultExt<T> {
+    fn py_context(self, py: Python) -> Result<T>;
+}
+
+impl<T> PyResultExt<T> for PyResult<T> {
+    fn py_context(self, py: Python) -> Result<T> {
+        self.map_err(|error| Error::from_py_err(py, error))
+    }
 }
 
 impl From<geoengine_datatypes::error::Error> for Error {
@@ -42,3 +241,20 @@ impl From<geoengine_operators::error::Error> for Error {
         Self::Operator { source }
     }
 }
+
+impl From<geoengine_services::error::Error> for Error {
+    fn from(source: geoengine_services::error::Error) -> Self {
+        Self::Service { source }
+    }
+}
+
+impl From<Error> for geoengine_operators::error::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Operator { source } => source,
+            error => Self::InvalidOperatorSpec {
+                reason: error.to_string(),
+            },
+        }
+    }
+}
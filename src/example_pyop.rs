@@ -1,24 +1,35 @@
 use futures::stream::BoxStream;
-use futures::StreamExt;
-use geoengine_datatypes::raster::{Grid2D, Pixel, Raster, RasterTile2D};
+use geoengine_datatypes::primitives::Measurement;
+use geoengine_datatypes::raster::{Pixel, RasterDataType, RasterTile2D};
 use geoengine_operators::engine::{
     ExecutionContext, InitializedOperator, InitializedOperatorBase, InitializedRasterOperator,
-    InitializedVectorOperator, QueryContext, QueryProcessor, QueryRectangle, RasterOperator,
-    RasterQueryProcessor, RasterResultDescriptor, TypedRasterQueryProcessor, VectorOperator,
+    InitializedVectorOperator, QueryContext, QueryRectangle, RasterOperator, RasterQueryProcessor,
+    RasterResultDescriptor, TypedRasterQueryProcessor, TypedVectorQueryProcessor, VectorOperator,
 };
-use geoengine_operators::error::Error as GeoengineOperatorsError;
 use geoengine_operators::util::Result;
 use serde::{Deserialize, Serialize};
 
-use ndarray::{s, stack, Array, Array1, Array2, Axis, Dim, OwnedArcRepr};
-use numpy::{IntoPyArray, PyArray, PyArray2, ToPyArray};
-use pyo3::prelude::*;
-use pyo3::{
-    types::{PyAny, PyModule},
-    Py, Python,
+use crate::convert::{check_kwargs, py_to_grid_data, PyOutputBuffer};
+use crate::error::Error;
+use crate::model::{PyModel, PyModelParams};
+use crate::plugin::plugin_registry;
+use crate::reload::Reloadable;
+use crate::runner::{PyOutput, PyRunner};
+use crate::schema::validate_parameters;
+use crate::script::{LoadedScript, PyHooks, PyScript, PyScriptModule};
+use crate::state::{PyState, PyStateScope};
+use crate::subprocess::{PyBackend, PyResourceLimits, PySubprocessRunner, PyWorkerLease};
+use crate::util::{
+    call_on_raster_processors, initialize_sources, output_no_data_value, output_tile,
 };
+use crate::worker::configured_timeout;
+use pyo3::prelude::*;
+use std::marker::PhantomData;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
 
-/// An example operator that adds `x` to its input raster stream
+/// An operator that processes its input raster stream with the functions of a Python script
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PyOperator {
     pub params: PyOperatorParams,
@@ -29,55 +40,236 @@ pub struct PyOperator {
 /// The parameter spec for `PyOperator`
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PyOperatorParams {
-    /// Number of components for PCA
-    pub n_comp: f64,
+    /// The Python script that implements the operator
+    pub script: PyScript,
+    /// The name under which the script is loaded as a Python module
+    pub module_name: String,
+    /// The functions of the script that are called during processing, a plugin script declares
+    /// its own
+    pub hooks: PyHooks,
+    #[serde(flatten)]
+    pub options: PyOperatorOptions,
+}
+
+/// The parameters of `PyOperator` and `PyPluginOperator` that do not select the script
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PyOperatorOptions {
+    /// Parameters that are passed to the script's functions as keyword arguments
+    #[serde(default)]
+    pub parameters: serde_json::Value,
+    /// Whether each query or the whole operator gets its own Python state object
+    #[serde(default)]
+    pub state: PyStateScope,
+    /// Whether the fitted state is saved to or loaded from the model store
+    #[serde(default)]
+    pub model: Option<PyModelParams>,
+    /// How long a call of a hook may take, defaults to the configured `python.timeout_ms`
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Number of tiles that are passed to the hooks in one call, as a list of tile arrays
+    #[serde(default)]
+    pub batch_size: Option<NonZeroUsize>,
+    /// Where the script runs, defaults to the configured `python.backend`
+    #[serde(default)]
+    pub backend: Option<PyBackend>,
+    /// The memory and CPU time that a query may use, requires the subprocess backend
+    #[serde(default)]
+    pub limits: PyResourceLimits,
+    /// Up to how many bytes of pixels the training phase keeps for the transform phase instead of
+    /// querying the sources again, nothing is kept by default
+    #[serde(default)]
+    pub fit_buffer_bytes: Option<usize>,
+    /// The data type of the returned tiles, defaults to the input data type
+    #[serde(default)]
+    pub output_data_type: Option<RasterDataType>,
+    /// The measurement of the returned tiles, defaults to the input measurement
+    #[serde(default)]
+    pub output_measurement: Option<Measurement>,
+    /// The no-data value of the returned tiles, which masked elements and NaNs of the script's
+    /// output become, defaults to the input no-data value
+    #[serde(default)]
+    pub output_no_data_value: Option<f64>,
 }
 
 #[typetag::serde]
 impl RasterOperator for PyOperator {
     fn initialize(
-        mut self: Box<Self>,
+        self: Box<Self>,
         context: &dyn ExecutionContext,
     ) -> Result<Box<InitializedRasterOperator>> {
-        if !self.vector_sources.is_empty() {
-            return Err(GeoengineOperatorsError::InvalidNumberOfVectorInputs {
-                expected: 0..1,
-                found: self.vector_sources.len(),
-            });
-        }
+        let (initialized_rasters, initialized_vectors) =
+            initialize_sources(self.raster_sources, self.vector_sources, context)?;
 
-        if self.raster_sources.len() != 1 {
-            return Err(GeoengineOperatorsError::InvalidNumberOfRasterInputs {
-                expected: 1..2,
-                found: self.raster_sources.len(),
-            });
-        }
+        let input_descriptor = initialized_rasters[0].result_descriptor();
+
+        let result_descriptor = RasterResultDescriptor {
+            data_type: self
+                .params
+                .options
+                .output_data_type
+                .unwrap_or(input_descriptor.data_type),
+            spatial_reference: input_descriptor.spatial_reference,
+            measurement: self
+                .params
+                .options
+                .output_measurement
+                .clone()
+                .unwrap_or_else(|| input_descriptor.measurement.clone()),
+        };
+
+        check_kwargs(&self.params.options.parameters)?;
 
-        let initialized_raster = self
-            .raster_sources
-            .pop()
-            .expect("checked")
-            .initialize(context)?;
-        let result_descriptor = initialized_raster.result_descriptor().clone();
+        let timeout = configured_timeout(self.params.options.timeout_ms)?;
+
+        let params = &self.params;
+        let backend = PyBackend::configured(params.options.backend)?;
+        let script = Reloadable::load(&params.module_name, || {
+            PyOperatorScript::load(
+                &params.script,
+                &params.module_name,
+                &params.hooks,
+                &params.options,
+                backend,
+                &initialized_vectors,
+            )
+        })?;
 
         let initialized_operator = InitializedPyOperator {
             params: self.params,
-            raster_sources: vec![initialized_raster],
-            vector_sources: vec![],
+            script,
+            raster_sources: initialized_rasters,
+            vector_sources: initialized_vectors,
             result_descriptor,
-            state: (),
+            timeout,
         };
 
         Ok(initialized_operator.boxed())
     }
 }
 
+/// Reject the features that only the in-process backend provides
+fn check_subprocess_support(
+    options: &PyOperatorOptions,
+    vector_sources: &[Box<InitializedVectorOperator>],
+) -> Result<()> {
+    if !vector_sources.is_empty() {
+        return Err(Error::UnsupportedByBackend {
+            reason: "vector inputs are only supported in process".to_string(),
+        }
+        .into());
+    }
+
+    if options.batch_size.is_some() {
+        return Err(Error::UnsupportedByBackend {
+            reason: "batching tiles is only supported in process".to_string(),
+        }
+        .into());
+    }
+
+    if options.state == PyStateScope::Operator {
+        return Err(Error::UnsupportedByBackend {
+            reason: "an operator scoped state is only supported in process".to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// What an initialized operator derives from its script
+///
+/// In hot reload mode, see `crate::reload`, this is loaded again after the script changed.
+pub struct PyOperatorScript {
+    /// The parameters, validated against the script's schema and completed by its defaults
+    pub parameters: serde_json::Value,
+    pub hooks: PyHooks,
+    pub module: PyOperatorModule,
+    pub state: Option<Arc<PyState>>,
+    pub model: Option<Arc<PyModel>>,
+}
+
+impl PyOperatorScript {
+    /// Load the script and derive what the given backend needs, shared by all Python operators
+    pub(crate) fn load(
+        script: &PyScript,
+        module_name: &str,
+        hooks: &PyHooks,
+        options: &PyOperatorOptions,
+        backend: PyBackend,
+        vector_sources: &[Box<InitializedVectorOperator>],
+    ) -> Result<Self> {
+        let loaded = script.load(module_name)?;
+
+        // a reloaded plugin may declare other hooks
+        let hooks = match script {
+            PyScript::Plugin(name) => plugin_registry()?.get(name)?.declaration.hooks.clone(),
+            PyScript::Path(_) | PyScript::Source(_) => hooks.clone(),
+        };
+
+        // the declared parameters are checked before any tile is queried
+        let parameters = match loaded.parameter_schema()? {
+            Some(schema) => validate_parameters(&schema, &options.parameters)?,
+            None => options.parameters.clone(),
+        };
+
+        let model = options
+            .model
+            .as_ref()
+            .map(|model| PyModel::new(model, &loaded, &parameters))
+            .transpose()?
+            .map(Arc::new);
+
+        let (module, state) = match backend {
+            PyBackend::InProcess => {
+                if !options.limits.is_unlimited() {
+                    return Err(Error::UnsupportedByBackend {
+                        reason: "resource limits are only enforced for worker processes"
+                            .to_string(),
+                    }
+                    .into());
+                }
+
+                let module = loaded.compile(hooks.clone())?;
+
+                let state = PyState::shared(options.state, &module, &parameters, model.as_deref())?;
+
+                (PyOperatorModule::InProcess(module), state)
+            }
+            PyBackend::Subprocess => {
+                check_subprocess_support(options, vector_sources)?;
+
+                // loading the script once reports syntax errors and missing hooks right away
+                PyWorkerLease::acquire(&loaded, &hooks)?;
+
+                (PyOperatorModule::Subprocess(loaded), None)
+            }
+        };
+
+        Ok(Self {
+            parameters,
+            hooks,
+            module,
+            state,
+            model,
+        })
+    }
+}
+
+/// The script of an initialized operator, depending on its backend
+pub enum PyOperatorModule {
+    /// The compiled module in the embedded interpreter
+    InProcess(PyScriptModule),
+    /// The script that is loaded into a worker process for every query
+    Subprocess(LoadedScript),
+}
+
 pub struct InitializedPyOperator {
     pub params: PyOperatorParams,
+    pub script: Reloadable<PyOperatorScript>,
     pub raster_sources: Vec<Box<InitializedRasterOperator>>,
     pub vector_sources: Vec<Box<InitializedVectorOperator>>,
     pub result_descriptor: RasterResultDescriptor,
-    pub state: (),
+    pub timeout: Option<Duration>,
 }
 
 impl InitializedOperatorBase for InitializedPyOperator {
@@ -104,305 +296,1408 @@ impl InitializedOperatorBase for InitializedPyOperator {
     }
 }
 
-impl InitializedOperator<RasterResultDescriptor, TypedRasterQueryProcessor>
-    for InitializedPyOperator
-{
-    fn query_processor(&self) -> Result<TypedRasterQueryProcessor> {
-        let typed_raster_processor = self.raster_sources[0].query_processor()?;
-        let add_value = self.params.n_comp;
-
-        Ok(match typed_raster_processor {
-            TypedRasterQueryProcessor::U8(p) => {
-                TypedRasterQueryProcessor::U8(PyProcessor::new(p, add_value).boxed())
+/// Create a `PyProcessor` whose output pixel type corresponds to `$output_data_type`
+macro_rules! py_processor_with_output_type {
+    ($output_data_type:expr, $($arg:expr),*) => {
+        match $output_data_type {
+            RasterDataType::U8 => {
+                TypedRasterQueryProcessor::U8(PyProcessor::new($($arg),*)?.boxed())
             }
-            TypedRasterQueryProcessor::U16(p) => {
-                TypedRasterQueryProcessor::U16(PyProcessor::new(p, add_value).boxed())
+            RasterDataType::U16 => {
+                TypedRasterQueryProcessor::U16(PyProcessor::new($($arg),*)?.boxed())
             }
-            TypedRasterQueryProcessor::U32(p) => {
-                TypedRasterQueryProcessor::U32(PyProcessor::new(p, add_value).boxed())
+            RasterDataType::U32 => {
+                TypedRasterQueryProcessor::U32(PyProcessor::new($($arg),*)?.boxed())
             }
-            TypedRasterQueryProcessor::U64(p) => {
-                TypedRasterQueryProcessor::U64(PyProcessor::new(p, add_value).boxed())
+            RasterDataType::U64 => {
+                TypedRasterQueryProcessor::U64(PyProcessor::new($($arg),*)?.boxed())
             }
-            TypedRasterQueryProcessor::I8(p) => {
-                TypedRasterQueryProcessor::I8(PyProcessor::new(p, add_value).boxed())
+            RasterDataType::I8 => {
+                TypedRasterQueryProcessor::I8(PyProcessor::new($($arg),*)?.boxed())
             }
-            TypedRasterQueryProcessor::I16(p) => {
-                TypedRasterQueryProcessor::I16(PyProcessor::new(p, add_value).boxed())
+            RasterDataType::I16 => {
+                TypedRasterQueryProcessor::I16(PyProcessor::new($($arg),*)?.boxed())
             }
-            TypedRasterQueryProcessor::I32(p) => {
-                TypedRasterQueryProcessor::I32(PyProcessor::new(p, add_value).boxed())
+            RasterDataType::I32 => {
+                TypedRasterQueryProcessor::I32(PyProcessor::new($($arg),*)?.boxed())
             }
-            TypedRasterQueryProcessor::I64(p) => {
-                TypedRasterQueryProcessor::I64(PyProcessor::new(p, add_value).boxed())
+            RasterDataType::I64 => {
+                TypedRasterQueryProcessor::I64(PyProcessor::new($($arg),*)?.boxed())
             }
-            TypedRasterQueryProcessor::F32(p) => {
-                TypedRasterQueryProcessor::F32(PyProcessor::new(p, add_value).boxed())
+            RasterDataType::F32 => {
+                TypedRasterQueryProcessor::F32(PyProcessor::new($($arg),*)?.boxed())
             }
-            TypedRasterQueryProcessor::F64(p) => {
-                TypedRasterQueryProcessor::F64(PyProcessor::new(p, add_value).boxed())
+            RasterDataType::F64 => {
+                TypedRasterQueryProcessor::F64(PyProcessor::new($($arg),*)?.boxed())
             }
-        })
-    }
+        }
+    };
 }
 
-pub struct PyProcessor<T>
-where
-    T: Pixel,
+impl InitializedOperator<RasterResultDescriptor, TypedRasterQueryProcessor>
+    for InitializedPyOperator
 {
-    raster: Box<dyn RasterQueryProcessor<RasterType = T>>,
-    add_value: T,
-    pymod: Py<PyModule>,
-}
-
-// unsafe impl<T> Send for PyProcessor<T> where T: Pixel {}
-// unsafe impl<T> Sync for PyProcessor<T> where T: Pixel {}
+    fn query_processor(&self) -> Result<TypedRasterQueryProcessor> {
+        let vectors = self
+            .vector_sources
+            .iter()
+            .map(|vector| vector.query_processor())
+            .collect::<Result<Vec<_>>>()?;
 
-impl<T> PyProcessor<T>
-where
-    T: Pixel + numpy::Element,
-{
-    pub fn new(raster: Box<dyn RasterQueryProcessor<RasterType = T>>, add_value: f64) -> Self {
-        // temporary py stuff
-        let gil = Python::acquire_gil();
-        let py = gil.python();
-
-        // das PyModule wird hier in ein Pyo3 Rust Objekt gepackt um als Feld im Struct abrufbar zu sein.
-        let py_mdl: Py<PyModule> =
-            PyModule::from_code(py, include_str!("ipca.py"), "activators.py", "activators")
-                .unwrap()
-                .into_py(py);
+        let output_data_type = self.result_descriptor.data_type;
 
-        Self {
-            raster,
-            add_value: T::from_(add_value),
-            pymod: py_mdl,
-        }
-    }
+        let params = &self.params;
+        let script = self.script.current(|| {
+            PyOperatorScript::load(
+                &params.script,
+                &params.module_name,
+                &params.hooks,
+                &params.options,
+                PyBackend::configured(params.options.backend)?,
+                &self.vector_sources,
+            )
+        })?;
 
-    /// Wrapper Methode um eine Funktion aus dem Pythonmodul auszuführen.
-    fn add(&self, num: f64) {
-        let res: f64 = Python::with_gil(|py| {
-            self.pymod
-                .getattr(py, "add")
-                .unwrap()
-                .call1(py, (num,))
-                .unwrap()
-                .extract(py)
-                .unwrap()
+        let processor = call_on_raster_processors!(self.raster_sources, rasters => {
+            py_processor_with_output_type!(output_data_type, rasters, vectors, self, &script)
         });
-    }
 
-    /// Getter Methode um ein Feld im Pythonmodul abzufragen.
-    pub fn get(&self) -> f64 {
-        let res: f64 =
-            Python::with_gil(|py| self.pymod.getattr(py, "i").unwrap().extract(py).unwrap());
-        res
+        Ok(processor)
     }
+}
 
-    fn fit_tiles(&self, tile: RasterTile2D<T>) -> Result<RasterTile2D<T>> {
-        //
-
-        let data: Vec<T> = tile.grid_array.data.clone();
-        let ar: ndarray::Array2<T> = Array2::from_shape_vec((600, 600), data.to_owned())
-            .unwrap()
-            .to_owned();
+pub struct PyProcessor<TIn, TOut>
+where
+    TIn: Pixel,
+    TOut: Pixel,
+{
+    runner: PyProcessorRunner<TIn>,
+    output_no_data_value: Option<f64>,
+    _output: PhantomData<TOut>,
+}
 
-        let gil = Python::acquire_gil();
-        let py = gil.python();
-        let pythonized_data = PyArray2::from_owned_array(py, ar);
+enum PyProcessorRunner<TIn>
+where
+    TIn: Pixel,
+{
+    InProcess(PyRunner<TIn>),
+    Subprocess(PySubprocessRunner<TIn>),
+}
 
-        self.pymod
-            .as_ref(py)
-            .call("partial_fit_ipca", (pythonized_data,), None);
+impl<TIn, TOut> PyProcessor<TIn, TOut>
+where
+    TIn: Pixel + numpy::Element,
+    TOut: Pixel + numpy::Element,
+{
+    pub fn new(
+        rasters: Vec<Box<dyn RasterQueryProcessor<RasterType = TIn>>>,
+        vectors: Vec<TypedVectorQueryProcessor>,
+        operator: &InitializedPyOperator,
+        script: &PyOperatorScript,
+    ) -> Result<Self> {
+        let runner = match &script.module {
+            PyOperatorModule::InProcess(module) => PyProcessorRunner::InProcess(
+                PyRunner::new(
+                    rasters,
+                    vectors,
+                    Python::with_gil(|py| module.clone_ref(py)),
+                    &script.parameters,
+                    operator.result_descriptor.spatial_reference,
+                )?
+                .with_shared_state(script.state.clone())
+                .with_model(script.model.clone())
+                .with_batch_size(operator.params.options.batch_size)
+                .with_timeout(operator.timeout)
+                .with_fit_buffer(operator.params.options.fit_buffer_bytes),
+            ),
+            PyOperatorModule::Subprocess(loaded) => PyProcessorRunner::Subprocess(
+                PySubprocessRunner::new(
+                    rasters,
+                    loaded.clone(),
+                    script.hooks.clone(),
+                    script.parameters.clone(),
+                    operator.result_descriptor.spatial_reference,
+                )
+                .with_model(script.model.clone())
+                .with_timeout(operator.timeout)
+                .with_limits(operator.params.options.limits)
+                .with_fit_buffer(operator.params.options.fit_buffer_bytes)
+                .with_output_no_data_value(operator.params.options.output_no_data_value),
+            ),
+        };
 
-        Ok(RasterTile2D::new(
-            tile.time,
-            tile.tile_position,
-            tile.geo_transform(),
-            Grid2D::new(tile.grid_array.shape, data, tile.grid_array.no_data_value)?,
-        ))
+        Ok(Self {
+            runner,
+            output_no_data_value: operator.params.options.output_no_data_value,
+            _output: PhantomData,
+        })
     }
+}
 
-    fn transform_tiles(&self, tile: RasterTile2D<T>) -> Result<RasterTile2D<T>> {
-        let data: Vec<T> = tile.grid_array.data.clone();
-        let ar: ndarray::Array2<T> = Array2::from_shape_vec((600, 600), data.to_owned())
-            .unwrap()
-            .to_owned();
+/// Builds the output tiles from the arrays returned by the script and the input tiles
+///
+/// The tile hook gets a zeroed `out` array of the output data type. If it writes its result into
+/// that array and returns it or `None`, its buffer becomes the new tile without a copy.
+struct TileOutput<TIn, TOut> {
+    /// The declared `output_no_data_value`
+    no_data_value: Option<f64>,
+    _types: PhantomData<fn(TIn) -> TOut>,
+}
 
-        let gil = Python::acquire_gil();
-        let py = gil.python();
-        let pythonized_data = PyArray2::from_owned_array(py, ar);
+impl<TIn, TOut> PyOutput<TIn> for TileOutput<TIn, TOut>
+where
+    TIn: Pixel,
+    TOut: Pixel + numpy::Element,
+{
+    type Item = RasterTile2D<TOut>;
+    type Buffer = PyOutputBuffer<TOut>;
 
-        let new_data = self
-            .pymod
-            .as_ref(py)
-            .call("apply_ipca", (pythonized_data,), None)
-            .unwrap()
-            .downcast::<PyArray2<T>>()
-            .unwrap()
-            .to_vec()
-            .unwrap();
+    fn buffer<'py>(
+        &self,
+        py: Python<'py>,
+        tiles: &[RasterTile2D<TIn>],
+    ) -> PyResult<Option<(Self::Buffer, &'py PyAny)>> {
+        let buffer = PyOutputBuffer::new(py, tiles[0].grid_array.shape)?;
+        let out = buffer.array(py).as_ref();
 
-        Ok(RasterTile2D::new(
-            tile.time,
-            tile.tile_position,
-            tile.geo_transform(),
-            Grid2D::new(
-                tile.grid_array.shape,
-                new_data,
-                tile.grid_array.no_data_value,
-            )?,
-        ))
+        Ok(Some((buffer, out)))
     }
 
-    fn compute(&self, tile: RasterTile2D<T>) -> Result<RasterTile2D<T>> {
-        // source tile data
-        let data: &[T] = &tile.grid_array.data;
+    fn output(
+        &self,
+        py: Python,
+        result: PyObject,
+        buffer: Option<Self::Buffer>,
+        tiles: &[RasterTile2D<TIn>],
+    ) -> Result<RasterTile2D<TOut>> {
+        let tile = &tiles[0];
+        let no_data_value = output_no_data_value(tile, self.no_data_value);
 
-        // manipulate data
-        //                     (vvv--- hier wird nur nach vollständigen daten unterschieden---)
-        let new_data: Vec<T> = if let Some(no_data_value) = tile.grid_array.no_data_value {
-            data.iter()
-                .map(|&v| {
-                    if v == no_data_value {
-                        no_data_value
-                    } else {
-                        v + self.add_value
-                    }
-                })
-                .collect()
-        } else {
-            // ! welchen zweck erfüllt dieser block?
-            data.iter().map(|&v| v + self.add_value).collect()
+        let written = |buffer: &PyOutputBuffer<TOut>| {
+            let result = result.as_ref(py);
+            result.is_none() || buffer.is_result(result)
         };
 
-        // return raster tile with manipulated data
-        Ok(RasterTile2D::new(
-            tile.time,
-            tile.tile_position,
-            tile.geo_transform(),
-            Grid2D::new(
-                tile.grid_array.shape,
-                new_data,
-                tile.grid_array.no_data_value,
-            )?,
-        ))
+        let new_data = match buffer {
+            Some(buffer) if written(&buffer) => {
+                // the result must not keep the buffer's array alive
+                drop(result);
+                buffer.into_grid_data(py, no_data_value)?
+            }
+            _ => py_to_grid_data(result.as_ref(py), tile.grid_array.shape, no_data_value)?,
+        };
+
+        output_tile(tile, new_data, no_data_value)
     }
 }
 
-impl<T> RasterQueryProcessor for PyProcessor<T>
+impl<TIn, TOut> RasterQueryProcessor for PyProcessor<TIn, TOut>
 where
-    T: Pixel + numpy::Element,
+    TIn: Pixel + numpy::Element,
+    TOut: Pixel + numpy::Element,
 {
-    type RasterType = T;
+    type RasterType = TOut;
 
     fn raster_query<'a>(
         &'a self,
         query: QueryRectangle,
         ctx: &'a dyn QueryContext,
     ) -> Result<BoxStream<'a, Result<RasterTile2D<Self::RasterType>>>> {
-        let s1 = self.raster.query(query, ctx)?.map(move |raster_tile| {
-            let raster_tile = raster_tile.unwrap();
-
-            self.fit_tiles(raster_tile)
-        });
-        let s2 = self.raster.query(query, ctx)?.map(move |raster_tile| {
-            let raster_tile = raster_tile.unwrap();
-
-            self.transform_tiles(raster_tile)
-        });
+        Ok(match &self.runner {
+            PyProcessorRunner::InProcess(runner) => {
+                let output = TileOutput {
+                    no_data_value: self.output_no_data_value,
+                    _types: PhantomData,
+                };
 
-        let res = s1.chain(s2).boxed();
-        Ok(res)
+                runner.query(query, ctx, output)
+            }
+            PyProcessorRunner::Subprocess(runner) => runner.query(query, ctx),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use geoengine_datatypes::primitives::{
-        BoundingBox2D, Measurement, SpatialResolution, TimeInterval,
-    };
-    use geoengine_datatypes::raster::{RasterDataType, TileInformation};
-    use geoengine_datatypes::spatial_reference::SpatialReference;
-    use geoengine_operators::engine::{MockExecutionContext, MockQueryContext};
-    use geoengine_operators::mock::{MockRasterSource, MockRasterSourceParams};
+    use crate::model::PyModelMode;
+    use crate::reload::reload;
+    use crate::test_util::{mock_raster_source, query_rectangle, tile_u8};
+    use futures::StreamExt;
+    use geoengine_datatypes::collections::MultiPointCollection;
+    use geoengine_datatypes::primitives::{Measurement, MultiPoint, TimeInterval};
+    use geoengine_datatypes::raster::{Grid2D, RasterDataType, TileInformation};
+    use geoengine_operators::engine::{MockExecutionContext, MockQueryContext, QueryProcessor};
+    use geoengine_operators::mock::MockFeatureCollectionSource;
+    use geoengine_services::util::config::get_config_element;
+    use std::path::{Path, PathBuf};
+
+    const DOUBLE_SCRIPT: &str = "def tile(data, **kwargs):\n    return data[0] * 2\n";
+
+    fn inline_params(source: &str, tile: &str) -> PyOperatorParams {
+        PyOperatorParams {
+            script: PyScript::Source(source.to_string()),
+            module_name: "inline".to_string(),
+            hooks: PyHooks {
+                tile: tile.to_string(),
+                fit: None,
+                setup: None,
+                teardown: None,
+            },
+            options: PyOperatorOptions {
+                parameters: serde_json::Value::Null,
+                state: PyStateScope::Query,
+                model: None,
+                timeout_ms: None,
+                batch_size: None,
+                backend: None,
+                limits: PyResourceLimits::default(),
+                fit_buffer_bytes: None,
+                output_data_type: None,
+                output_measurement: None,
+                output_no_data_value: None,
+            },
+        }
+    }
+
+    async fn query_u8(operator: PyOperator) -> Vec<RasterTile2D<u8>> {
+        let execution_context = MockExecutionContext::default();
+
+        let operator = operator.boxed().initialize(&execution_context).unwrap();
+        query_initialized_u8(operator.as_ref()).await
+    }
+
+    async fn query_initialized_u8(operator: &InitializedRasterOperator) -> Vec<RasterTile2D<u8>> {
+        let query_processor = operator.query_processor().unwrap().get_u8().unwrap();
+
+        query_processor
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .map(|tile| tile.unwrap())
+            .collect::<Vec<_>>()
+            .await
+    }
 
     #[tokio::test]
     async fn simple_raster() {
         // ausgangs raster tile (pre-state)
-        let raster_tile = RasterTile2D::new_with_tile_info(
-            TimeInterval::default(),
-            TileInformation {
-                global_geo_transform: Default::default(),
-                global_tile_position: [0, 0].into(),
-                tile_size_in_pixels: [4, 4].into(), // ? y = -1?
-            },
-            Grid2D::new(
-                [4, 4].into(),
-                vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16],
-                None,
-            )
-            .unwrap(),
+        let raster_tile = tile_u8(
+            [0, 0],
+            [4, 4],
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16],
         );
 
-        // kontext faktoren. nötig, aber hier eigentlich nicht weiter relevant?
-        // -> liefert aber auch die eigentlichen daten, die bearbeitet werden sollen.
-        let raster_source = MockRasterSource {
-            params: MockRasterSourceParams {
-                data: vec![raster_tile], // ! hier liegen die daten
-                result_descriptor: RasterResultDescriptor {
-                    data_type: RasterDataType::U8,
-                    spatial_reference: SpatialReference::epsg_4326().into(),
-                    measurement: Measurement::Unitless,
-                },
-            },
+        let operator = PyOperator {
+            params: inline_params(DOUBLE_SCRIPT, "tile"),
+            raster_sources: vec![mock_raster_source(vec![raster_tile])],
+            vector_sources: vec![],
+        };
+
+        let result = query_u8(operator).await;
+
+        let result_tile = tile_u8(
+            [0, 0],
+            [4, 4],
+            vec![2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32],
+        );
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0], result_tile);
+    }
+
+    #[tokio::test]
+    async fn non_square_and_edge_tiles() {
+        let tiles = vec![
+            tile_u8([0, 0], [2, 3], vec![1, 2, 3, 4, 5, 6]),
+            tile_u8([0, 1], [2, 1], vec![7, 8]),
+            tile_u8([1, 0], [1, 3], vec![9, 10, 11]),
+        ];
+
+        let operator = PyOperator {
+            params: inline_params(DOUBLE_SCRIPT, "tile"),
+            raster_sources: vec![mock_raster_source(tiles)],
+            vector_sources: vec![],
+        };
+
+        let result = query_u8(operator).await;
+
+        assert_eq!(
+            result,
+            vec![
+                tile_u8([0, 0], [2, 3], vec![2, 4, 6, 8, 10, 12]),
+                tile_u8([0, 1], [2, 1], vec![14, 16]),
+                tile_u8([1, 0], [1, 3], vec![18, 20, 22]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn tiles_can_be_written_into_out() {
+        let scripts = [
+            "def tile(data, out, **kwargs):\n    out[:] = data[0] * 2\n    return out\n",
+            "def tile(data, out, **kwargs):\n    out[:] = data[0] * 2\n",
+            // keeps the array alive, so the buffer must be copied
+            "kept = []\n\ndef tile(data, out, **kwargs):\n    out[:] = data[0] * 2\n    kept.append(out)\n",
+        ];
+
+        for script in scripts.iter() {
+            let operator = PyOperator {
+                params: inline_params(script, "tile"),
+                raster_sources: vec![mock_raster_source(vec![
+                    tile_u8([0, 0], [2, 3], vec![1, 2, 3, 4, 5, 6]),
+                    tile_u8([0, 1], [2, 1], vec![7, 8]),
+                ])],
+                vector_sources: vec![],
+            };
+
+            assert_eq!(
+                query_u8(operator).await,
+                vec![
+                    tile_u8([0, 0], [2, 3], vec![2, 4, 6, 8, 10, 12]),
+                    tile_u8([0, 1], [2, 1], vec![14, 16]),
+                ]
+            );
         }
-        .boxed();
+    }
 
+    #[tokio::test]
+    async fn output_shape_must_match_tile() {
         let operator = PyOperator {
-            params: PyOperatorParams { n_comp: 1. },
-            raster_sources: vec![raster_source],
+            params: inline_params("def tile(data, **kwargs):\n    return data[0].T\n", "tile"),
+            raster_sources: vec![mock_raster_source(vec![tile_u8(
+                [0, 0],
+                [2, 3],
+                vec![1, 2, 3, 4, 5, 6],
+            )])],
             vector_sources: vec![],
         };
 
-        let execution_context = MockExecutionContext::default();
+        assert!(query_error(operator).await.contains("InvalidPythonOutput"));
+    }
+
+    #[test]
+    fn missing_hooks() {
+        let mut params = inline_params("def tile(data):\n    return data\n\nfit = 42\n", "tile");
+        params.hooks.fit = Some("fit".to_string());
+
+        let operator = PyOperator {
+            params,
+            raster_sources: vec![mock_raster_source(vec![])],
+            vector_sources: vec![],
+        };
+
+        let result = operator
+            .boxed()
+            .initialize(&MockExecutionContext::default());
+
+        assert!(result.is_err());
+    }
+
+    const SCALING_SCRIPT: &str = "PARAMETERS = {\n    'factor': {'type': 'int', 'min': 1, 'max': 10},\n    'offset': {'type': 'int', 'default': 1},\n}\n\ndef tile(data, factor, offset, **kwargs):\n    return data[0] * factor + offset\n";
+
+    #[tokio::test]
+    async fn declared_parameters() {
+        let mut params = inline_params(SCALING_SCRIPT, "tile");
+        params.options.parameters = serde_json::json!({"factor": 2});
+
+        let operator = PyOperator {
+            params,
+            raster_sources: vec![mock_raster_source(vec![tile_u8(
+                [0, 0],
+                [2, 2],
+                vec![1, 2, 3, 4],
+            )])],
+            vector_sources: vec![],
+        };
+
+        let result = query_u8(operator).await;
+
+        assert_eq!(result, vec![tile_u8([0, 0], [2, 2], vec![3, 5, 7, 9])]);
+    }
+
+    #[test]
+    fn parameters_violate_schema() {
+        let mut params = inline_params(SCALING_SCRIPT, "tile");
+        params.options.parameters = serde_json::json!({"factr": 2, "offset": "1"});
+
+        let operator = PyOperator {
+            params,
+            raster_sources: vec![mock_raster_source(vec![])],
+            vector_sources: vec![],
+        };
+
+        let error = operator
+            .boxed()
+            .initialize(&MockExecutionContext::default())
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error.contains("PythonParametersSchemaError"));
+        assert!(error.contains("\"factr\" is not a parameter of the script"));
+        assert!(error.contains("\"factor\" is required"));
+        assert!(error.contains("\"offset\" must be of type int"));
+    }
+
+    #[tokio::test]
+    async fn fit_before_transform() {
+        let mut params = inline_params(
+            "fitted = []\n\ndef fit(data, **kwargs):\n    fitted.append(data.shape)\n\ndef tile(data, **kwargs):\n    return data[0] * 0 + len(fitted)\n",
+            "tile",
+        );
+        params.hooks.fit = Some("fit".to_string());
+
+        let operator = PyOperator {
+            params,
+            raster_sources: vec![mock_raster_source(vec![
+                tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4]),
+                tile_u8([0, 1], [2, 2], vec![5, 6, 7, 8]),
+                tile_u8([1, 0], [2, 2], vec![9, 10, 11, 12]),
+            ])],
+            vector_sources: vec![],
+        };
+
+        let result = query_u8(operator).await;
+
+        // every tile is emitted once and only after all tiles were fitted
+        assert_eq!(
+            result,
+            vec![
+                tile_u8([0, 0], [2, 2], vec![3, 3, 3, 3]),
+                tile_u8([0, 1], [2, 2], vec![3, 3, 3, 3]),
+                tile_u8([1, 0], [2, 2], vec![3, 3, 3, 3]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn both_phases_see_the_same_tiles() {
+        let source = "fitted = []\n\ndef fit(data, **kwargs):\n    fitted.append(data.tolist())\n\ndef tile(data, **kwargs):\n    if data.tolist() != fitted.pop(0):\n        raise ValueError('the tile was not fitted')\n    return data[0]\n";
+        let tiles = vec![
+            tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4]),
+            tile_u8([0, 1], [2, 2], vec![5, 6, 7, 8]),
+            tile_u8([1, 0], [2, 2], vec![9, 10, 11, 12]),
+        ];
+
+        for &backend in &[PyBackend::InProcess, PyBackend::Subprocess] {
+            let mut params = inline_params(source, "tile");
+            params.hooks.fit = Some("fit".to_string());
+            params.options.backend = Some(backend);
+
+            let operator = PyOperator {
+                params,
+                raster_sources: vec![mock_raster_source(tiles.clone())],
+                vector_sources: vec![],
+            };
+
+            // the transform phase queries the source again and gets the fitted tiles in order
+            assert_eq!(query_u8(operator).await, tiles);
+        }
+    }
+
+    #[tokio::test]
+    async fn fit_errors_end_the_query() {
+        let mut params = inline_params(
+            "def fit(data, **kwargs):\n    raise ValueError('cannot fit')\n\ndef tile(data, **kwargs):\n    return data[0]\n",
+            "tile",
+        );
+        params.hooks.fit = Some("fit".to_string());
+
+        let operator = PyOperator {
+            params,
+            raster_sources: vec![mock_raster_source(vec![
+                tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4]),
+                tile_u8([0, 1], [2, 2], vec![5, 6, 7, 8]),
+            ])],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
 
-        // * hier kommt ein InitializedADDXOperator raus
-        let operator = operator.boxed().initialize(&execution_context).unwrap();
         let query_processor = operator.query_processor().unwrap().get_u8().unwrap();
 
         let result = query_processor
-            .query(
-                QueryRectangle {
-                    bbox: BoundingBox2D::new((0.0, 0.0).into(), (2.0, 2.0).into()).unwrap(),
-                    time_interval: Default::default(),
-                    spatial_resolution: SpatialResolution::new(1., 1.).unwrap(),
-                },
-                &MockQueryContext::new(0),
-            )
+            .query(query_rectangle(), &MockQueryContext::new(0))
             .unwrap()
-            .map(|tile| tile.unwrap())
             .collect::<Vec<_>>()
             .await;
 
-        let result_tile = RasterTile2D::new_with_tile_info(
-            TimeInterval::default(),
-            TileInformation {
-                global_geo_transform: Default::default(),
-                global_tile_position: [0, 0].into(),
-                tile_size_in_pixels: [4, 4].into(),
-            },
-            Grid2D::new(
-                [4, 4].into(),
-                vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-                None,
-            )
-            .unwrap(),
+        assert_eq!(result.len(), 1);
+        assert!(result[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn python_exceptions_become_errors() {
+        let operator = PyOperator {
+            params: inline_params(
+                "def tile(data, **kwargs):\n    raise ValueError('broken tile')\n",
+                "tile",
+            ),
+            raster_sources: vec![mock_raster_source(vec![tile_u8(
+                [0, 0],
+                [2, 2],
+                vec![1, 2, 3, 4],
+            )])],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        let query_processor = operator.query_processor().unwrap().get_u8().unwrap();
+
+        let result = query_processor
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(result.len(), 1);
+
+        let error = result[0].as_ref().unwrap_err().to_string();
+        assert!(error.contains("ValueError"));
+        assert!(error.contains("broken tile"));
+        assert!(error.contains("in tile"));
+    }
+
+    #[tokio::test]
+    async fn failing_setup() {
+        let mut params = inline_params(
+            "def tile(data, **kwargs):\n    return data[0]\n\ndef setup(**kwargs):\n    raise RuntimeError('no model')\n",
+            "tile",
         );
+        params.hooks.setup = Some("setup".to_string());
+
+        let query_scoped = PyOperator {
+            params: params.clone(),
+            raster_sources: vec![mock_raster_source(vec![tile_u8(
+                [0, 0],
+                [2, 2],
+                vec![1, 2, 3, 4],
+            )])],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        let result = query_scoped
+            .query_processor()
+            .unwrap()
+            .get_u8()
+            .unwrap()
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
 
         assert_eq!(result.len(), 1);
-        assert_eq!(result[0], result_tile);
+        assert!(result[0].is_err());
+
+        params.options.state = PyStateScope::Operator;
+
+        let operator_scoped = PyOperator {
+            params,
+            raster_sources: vec![mock_raster_source(vec![])],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default());
+
+        assert!(operator_scoped.is_err());
+    }
+
+    const COUNTING_SCRIPT: &str = "def setup(state, **kwargs):\n    state.count = 0\n\ndef fit(data, state, **kwargs):\n    state.count += 1\n\ndef tile(data, state, **kwargs):\n    return data[0] * 0 + state.count\n";
+
+    fn counting_operator(state: PyStateScope) -> Box<InitializedRasterOperator> {
+        let mut params = inline_params(COUNTING_SCRIPT, "tile");
+        params.hooks.fit = Some("fit".to_string());
+        params.hooks.setup = Some("setup".to_string());
+        params.options.state = state;
+
+        PyOperator {
+            params,
+            raster_sources: vec![mock_raster_source(vec![
+                tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4]),
+                tile_u8([0, 1], [2, 2], vec![5, 6, 7, 8]),
+            ])],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn query_state_is_isolated() {
+        let operator = counting_operator(PyStateScope::Query);
+        let query_processor = operator.query_processor().unwrap().get_u8().unwrap();
+        let ctx = MockQueryContext::new(0);
+
+        let query = || {
+            query_processor
+                .query(query_rectangle(), &ctx)
+                .unwrap()
+                .map(|tile| tile.unwrap().grid_array.data)
+                .collect::<Vec<_>>()
+        };
+
+        let (first, second) = futures::join!(query(), query());
+
+        assert_eq!(first, vec![vec![2; 4], vec![2; 4]]);
+        assert_eq!(second, vec![vec![2; 4], vec![2; 4]]);
+    }
+
+    #[tokio::test]
+    async fn operator_state_is_shared() {
+        let operator = counting_operator(PyStateScope::Operator);
+        let ctx = MockQueryContext::new(0);
+
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            let query_processor = operator.query_processor().unwrap().get_u8().unwrap();
+
+            results.push(
+                query_processor
+                    .query(query_rectangle(), &ctx)
+                    .unwrap()
+                    .map(|tile| tile.unwrap().grid_array.data)
+                    .collect::<Vec<_>>()
+                    .await,
+            );
+        }
+
+        // the second query continues fitting the state of the first one
+        assert_eq!(
+            results,
+            vec![vec![vec![2; 4], vec![2; 4]], vec![vec![4; 4], vec![4; 4]]]
+        );
+    }
+
+    /// Removes a directory when dropped, even if the test fails
+    struct RemoveDirOnDrop(PathBuf);
+
+    impl Drop for RemoveDirOnDrop {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn saved_models_are_loaded() {
+        let model_root = get_config_element::<crate::config::Python>()
+            .unwrap()
+            .model_root;
+        let model_dir = RemoveDirOnDrop(model_root.join("counting-test"));
+
+        let model_operator = |mode: PyModelMode, tiles: Vec<RasterTile2D<u8>>| {
+            let mut params = inline_params(COUNTING_SCRIPT, "tile");
+            params.hooks.fit = Some("fit".to_string());
+            params.hooks.setup = Some("setup".to_string());
+            params.options.model = Some(PyModelParams {
+                mode,
+                id: "counting-test".to_string(),
+            });
+
+            PyOperator {
+                params,
+                raster_sources: vec![mock_raster_source(tiles)],
+                vector_sources: vec![],
+            }
+        };
+
+        let tiles = vec![
+            tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4]),
+            tile_u8([0, 1], [2, 2], vec![5, 6, 7, 8]),
+        ];
+
+        // fitting without saving leaves the model store untouched
+        let fitted = query_u8(model_operator(PyModelMode::Fit, tiles.clone())).await;
+        assert_eq!(fitted.len(), 2);
+        assert!(!model_dir.0.exists());
+
+        let fitted = query_u8(model_operator(PyModelMode::FitAndSave, tiles.clone())).await;
+        assert_eq!(fitted.len(), 2);
+        assert!(fitted.iter().all(|tile| tile.grid_array.data == vec![2; 4]));
+
+        // loading skips setup and fit, so the count of the saved state is kept
+        let mut more_tiles = tiles;
+        more_tiles.push(tile_u8([1, 0], [2, 2], vec![9, 10, 11, 12]));
+        let loaded = query_u8(model_operator(PyModelMode::Load, more_tiles)).await;
+        assert_eq!(loaded.len(), 3);
+        assert!(loaded.iter().all(|tile| tile.grid_array.data == vec![2; 4]));
+    }
+
+    #[tokio::test]
+    async fn changed_scripts_are_reloaded() {
+        let script_root = get_config_element::<crate::config::Python>()
+            .unwrap()
+            .script_root;
+        let script_dir = RemoveDirOnDrop(script_root.join("reload-test"));
+        std::fs::create_dir_all(&script_dir.0).unwrap();
+
+        let backends = [
+            (PyBackend::InProcess, "in_process.py"),
+            (PyBackend::Subprocess, "subprocess.py"),
+        ];
+
+        for &(backend, file_name) in backends.iter() {
+            let script = script_dir.0.join(file_name);
+            std::fs::write(&script, DOUBLE_SCRIPT).unwrap();
+
+            let mut params = inline_params(DOUBLE_SCRIPT, "tile");
+            params.script = PyScript::Path(Path::new("reload-test").join(file_name));
+            params.options.backend = Some(backend);
+
+            let operator = PyOperator {
+                params,
+                raster_sources: vec![mock_raster_source(vec![tile_u8(
+                    [0, 0],
+                    [2, 2],
+                    vec![1, 2, 3, 4],
+                )])],
+                vector_sources: vec![],
+            }
+            .boxed()
+            .initialize(&MockExecutionContext::default())
+            .unwrap();
+
+            assert_eq!(
+                query_initialized_u8(operator.as_ref()).await,
+                vec![tile_u8([0, 0], [2, 2], vec![2, 4, 6, 8])]
+            );
+
+            std::fs::write(
+                &script,
+                "def tile(data, **kwargs):\n    return data[0] * 3\n",
+            )
+            .unwrap();
+            // what the watcher does when it sees the change with `python.hot_reload`
+            reload(&[script_root.clone()]);
+
+            assert_eq!(
+                query_initialized_u8(operator.as_ref()).await,
+                vec![tile_u8([0, 0], [2, 2], vec![3, 6, 9, 12])]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn output_type_and_measurement() {
+        let mut params = inline_params(
+            "import numpy as np\n\ndef tile(data, **kwargs):\n    return (data[0] / 2).astype(np.float32)\n",
+            "tile",
+        );
+        params.options.output_data_type = Some(RasterDataType::F32);
+        params.options.output_measurement = Some(Measurement::Continuous {
+            measurement: "score".to_string(),
+            unit: None,
+        });
+
+        let operator = PyOperator {
+            params,
+            raster_sources: vec![mock_raster_source(vec![tile_u8(
+                [0, 0],
+                [2, 2],
+                vec![1, 2, 3, 4],
+            )])],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        assert_eq!(operator.result_descriptor().data_type, RasterDataType::F32);
+        assert_eq!(
+            operator.result_descriptor().measurement,
+            Measurement::Continuous {
+                measurement: "score".to_string(),
+                unit: None,
+            }
+        );
+
+        let query_processor = operator.query_processor().unwrap().get_f32().unwrap();
+
+        let result = query_processor
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .map(|tile| tile.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].grid_array.data, vec![0.5, 1., 1.5, 2.]);
+    }
+
+    #[tokio::test]
+    async fn no_data_is_masked() {
+        let raster_tile = RasterTile2D::new_with_tile_info(
+            TimeInterval::default(),
+            TileInformation {
+                global_geo_transform: Default::default(),
+                global_tile_position: [0, 0].into(),
+                tile_size_in_pixels: [2, 2].into(),
+            },
+            Grid2D::new([2, 2].into(), vec![0, 2, 0, 4], Some(0)).unwrap(),
+        );
+
+        let operator = PyOperator {
+            params: inline_params(
+                "import numpy as np\n\ndef tile(data, **kwargs):\n    band = data[0]\n    return np.ma.masked_array(np.full(band.shape, band.mean(), dtype=np.uint8), mask=band.mask)\n",
+                "tile",
+            ),
+            raster_sources: vec![mock_raster_source(vec![raster_tile])],
+            vector_sources: vec![],
+        };
+
+        let result = query_u8(operator).await;
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].grid_array.data, vec![0, 3, 0, 3]);
+        assert_eq!(result[0].grid_array.no_data_value, Some(0));
+    }
+
+    #[tokio::test]
+    async fn masked_output_requires_a_no_data_value() {
+        let source = "import numpy as np\n\ndef tile(data, **kwargs):\n    band = data[0]\n    return np.ma.masked_array(band.data, mask=band.data > 2)\n";
+
+        for &backend in &[PyBackend::InProcess, PyBackend::Subprocess] {
+            let operator = |output_no_data_value: Option<f64>| {
+                let mut params = inline_params(source, "tile");
+                params.options.backend = Some(backend);
+                params.options.output_no_data_value = output_no_data_value;
+
+                PyOperator {
+                    params,
+                    raster_sources: vec![mock_raster_source(vec![tile_u8(
+                        [0, 0],
+                        [2, 2],
+                        vec![1, 2, 3, 4],
+                    )])],
+                    vector_sources: vec![],
+                }
+            };
+
+            // the input has no no-data value either, so the masked elements cannot be marked
+            let error = query_error(operator(None)).await;
+            assert!(error.contains("InvalidPythonOutputError"), "{}", error);
+
+            let result = query_u8(operator(Some(255.))).await;
+            assert_eq!(result[0].grid_array.data, vec![1, 2, 255, 255]);
+            assert_eq!(result[0].grid_array.no_data_value, Some(255));
+        }
+    }
+
+    #[tokio::test]
+    async fn multiple_raster_inputs() {
+        let red = vec![
+            tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4]),
+            tile_u8([0, 1], [2, 2], vec![5, 6, 7, 8]),
+        ];
+        let nir = vec![
+            tile_u8([0, 0], [2, 2], vec![10, 20, 30, 40]),
+            tile_u8([0, 1], [2, 2], vec![50, 60, 70, 80]),
+        ];
+
+        let operator = PyOperator {
+            params: inline_params(
+                "def tile(data, **kwargs):\n    assert data.shape[0] == 2\n    return data[1] - data[0]\n",
+                "tile",
+            ),
+            raster_sources: vec![mock_raster_source(red), mock_raster_source(nir)],
+            vector_sources: vec![],
+        };
+
+        let result = query_u8(operator).await;
+
+        assert_eq!(
+            result,
+            vec![
+                tile_u8([0, 0], [2, 2], vec![9, 18, 27, 36]),
+                tile_u8([0, 1], [2, 2], vec![45, 54, 63, 72]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unaligned_raster_inputs() {
+        let operator = PyOperator {
+            params: inline_params(DOUBLE_SCRIPT, "tile"),
+            raster_sources: vec![
+                mock_raster_source(vec![tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4])]),
+                mock_raster_source(vec![tile_u8([0, 1], [2, 2], vec![1, 2, 3, 4])]),
+            ],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        let query_processor = operator.query_processor().unwrap().get_u8().unwrap();
+
+        let result = query_processor
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(result.len(), 1);
+        assert!(result[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn vector_inputs() {
+        let points = MultiPointCollection::from_data(
+            MultiPoint::many(vec![(0.5, 0.5), (1.5, 1.5), (1.5, 0.5)]).unwrap(),
+            vec![TimeInterval::default(); 3],
+            Default::default(),
+        )
+        .unwrap();
+
+        let operator = PyOperator {
+            params: inline_params(
+                "def tile(data, vectors, **kwargs):\n    assert vectors[0]['type'] == 'MultiPoint'\n    return data[0] + len(vectors[0]['geometries'])\n",
+                "tile",
+            ),
+            raster_sources: vec![mock_raster_source(vec![tile_u8(
+                [0, 0],
+                [2, 2],
+                vec![1, 2, 3, 4],
+            )])],
+            vector_sources: vec![MockFeatureCollectionSource::single(points).boxed()],
+        };
+
+        let result = query_u8(operator).await;
+
+        assert_eq!(result, vec![tile_u8([0, 0], [2, 2], vec![4, 5, 6, 7])]);
+    }
+
+    #[tokio::test]
+    async fn tile_metadata() {
+        let operator = PyOperator {
+            params: inline_params(
+                "def tile(data, metadata, **kwargs):\n    assert metadata['spatial_reference'] == 'EPSG:4326'\n    assert metadata['query']['bbox'] == [0., 0., 2., 2.]\n    row, column = metadata['tile_position']\n    assert metadata['geo_transform'][2] == column * 2\n    return data[0] * 0 + 10 * row + column\n",
+                "tile",
+            ),
+            raster_sources: vec![mock_raster_source(vec![
+                tile_u8([0, 1], [2, 2], vec![1, 2, 3, 4]),
+                tile_u8([1, 0], [2, 2], vec![5, 6, 7, 8]),
+            ])],
+            vector_sources: vec![],
+        };
+
+        let result = query_u8(operator).await;
+
+        assert_eq!(
+            result,
+            vec![
+                tile_u8([0, 1], [2, 2], vec![1; 4]),
+                tile_u8([1, 0], [2, 2], vec![10; 4]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sdk_tiles() {
+        let operator = PyOperator {
+            params: inline_params(
+                "from geoengine import raster_operator, Tile\n\n@raster_operator\ndef tile(tile, **kwargs):\n    assert isinstance(tile, Tile)\n    assert tile.query.bbox == (0., 0., 2., 2.)\n    row, column = tile.tile_position\n    assert tile.geo_transform.origin[0] == column * 2\n    assert not tile.no_data[0].is_no_data(0.)\n    return tile.band(0) * 0 + 10 * row + column\n",
+                "tile",
+            ),
+            raster_sources: vec![mock_raster_source(vec![
+                tile_u8([0, 1], [2, 2], vec![1, 2, 3, 4]),
+                tile_u8([1, 0], [2, 2], vec![5, 6, 7, 8]),
+            ])],
+            vector_sources: vec![],
+        };
+
+        let result = query_u8(operator).await;
+
+        assert_eq!(
+            result,
+            vec![
+                tile_u8([0, 1], [2, 2], vec![1; 4]),
+                tile_u8([1, 0], [2, 2], vec![10; 4]),
+            ]
+        );
+    }
+
+    #[test]
+    fn sdk_hooks_must_match_their_kind() {
+        let operator = PyOperator {
+            params: inline_params(
+                "from geoengine import fit\n\n@fit\ndef tile(tile, **kwargs):\n    pass\n",
+                "tile",
+            ),
+            raster_sources: vec![mock_raster_source(vec![])],
+            vector_sources: vec![],
+        };
+
+        let error = operator
+            .boxed()
+            .initialize(&MockExecutionContext::default())
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error.contains("MisplacedPythonHookError"));
+    }
+
+    #[tokio::test]
+    async fn batched_tiles() {
+        let mut params = inline_params(
+            "def fit(batch, state, **kwargs):\n    state.batches = getattr(state, 'batches', 0) + 1\n\ndef tile(batch, state, **kwargs):\n    assert isinstance(batch, list)\n    return [data[0] * 0 + state.batches * 10 + len(batch) for data in batch]\n",
+            "tile",
+        );
+        params.hooks.fit = Some("fit".to_string());
+        params.options.batch_size = NonZeroUsize::new(2);
+
+        let operator = PyOperator {
+            params,
+            raster_sources: vec![mock_raster_source(vec![
+                tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4]),
+                tile_u8([0, 1], [2, 1], vec![5, 6]),
+                tile_u8([1, 0], [1, 2], vec![7, 8]),
+            ])],
+            vector_sources: vec![],
+        };
+
+        let result = query_u8(operator).await;
+
+        // two fit calls, followed by a full batch and the remainder in stream order
+        assert_eq!(
+            result,
+            vec![
+                tile_u8([0, 0], [2, 2], vec![22; 4]),
+                tile_u8([0, 1], [2, 1], vec![22; 2]),
+                tile_u8([1, 0], [1, 2], vec![21; 2]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn batched_results_must_match_tiles() {
+        let mut params = inline_params(
+            "def tile(batch, **kwargs):\n    return [batch[0][0]]\n",
+            "tile",
+        );
+        params.options.batch_size = NonZeroUsize::new(2);
+
+        let operator = PyOperator {
+            params,
+            raster_sources: vec![mock_raster_source(vec![
+                tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4]),
+                tile_u8([0, 1], [2, 2], vec![5, 6, 7, 8]),
+            ])],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        let query_processor = operator.query_processor().unwrap().get_u8().unwrap();
+
+        let result = query_processor
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(result.len(), 1);
+        assert!(result[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn runaway_calls_time_out() {
+        let mut params = inline_params(
+            "def tile(data, **kwargs):\n    while True:\n        pass\n",
+            "tile",
+        );
+        params.options.timeout_ms = Some(100);
+
+        let operator = PyOperator {
+            params,
+            raster_sources: vec![mock_raster_source(vec![tile_u8(
+                [0, 0],
+                [2, 2],
+                vec![1, 2, 3, 4],
+            )])],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        let query_processor = operator.query_processor().unwrap().get_u8().unwrap();
+
+        let result = query_processor
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(result.len(), 1);
+        assert!(result[0]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("PythonTimeoutError"));
+    }
+
+    fn subprocess_operator(source: &str, tiles: Vec<RasterTile2D<u8>>) -> PyOperator {
+        let mut params = inline_params(source, "tile");
+        params.options.backend = Some(PyBackend::Subprocess);
+
+        PyOperator {
+            params,
+            raster_sources: vec![mock_raster_source(tiles)],
+            vector_sources: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn modules_with_the_same_name_are_separate() {
+        let tiles = vec![tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4])];
+
+        let doubling = PyOperator {
+            params: inline_params(DOUBLE_SCRIPT, "tile"),
+            raster_sources: vec![mock_raster_source(tiles.clone())],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        // compiled after the first operator, under the same module name
+        let incrementing = PyOperator {
+            params: inline_params(
+                "offset = 1\n\ndef tile(data, **kwargs):\n    return data[0] + offset\n",
+                "tile",
+            ),
+            raster_sources: vec![mock_raster_source(tiles)],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        let mut results = Vec::new();
+        for operator in &[doubling, incrementing] {
+            let tiles = operator
+                .query_processor()
+                .unwrap()
+                .get_u8()
+                .unwrap()
+                .query(query_rectangle(), &MockQueryContext::new(0))
+                .unwrap()
+                .map(|tile| tile.unwrap())
+                .collect::<Vec<_>>()
+                .await;
+
+            results.push(tiles);
+        }
+
+        assert_eq!(
+            results,
+            vec![
+                vec![tile_u8([0, 0], [2, 2], vec![2, 4, 6, 8])],
+                vec![tile_u8([0, 0], [2, 2], vec![2, 3, 4, 5])],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn subprocess_backend() {
+        let operator = subprocess_operator(
+            DOUBLE_SCRIPT,
+            vec![
+                tile_u8([0, 0], [2, 3], vec![1, 2, 3, 4, 5, 6]),
+                tile_u8([0, 1], [2, 1], vec![7, 8]),
+            ],
+        );
+
+        let result = query_u8(operator).await;
+
+        assert_eq!(
+            result,
+            vec![
+                tile_u8([0, 0], [2, 3], vec![2, 4, 6, 8, 10, 12]),
+                tile_u8([0, 1], [2, 1], vec![14, 16]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn subprocess_exceptions_become_errors() {
+        let operator = subprocess_operator(
+            "def tile(data, **kwargs):\n    raise ValueError('broken tile')\n",
+            vec![tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4])],
+        )
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        let query_processor = operator.query_processor().unwrap().get_u8().unwrap();
+
+        let result = query_processor
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(result.len(), 1);
+
+        let error = result[0].as_ref().unwrap_err().to_string();
+        assert!(error.contains("ValueError"));
+        assert!(error.contains("broken tile"));
+    }
+
+    #[tokio::test]
+    async fn subprocess_crashes_fail_only_the_query() {
+        let operator = subprocess_operator(
+            "import os\n\ndef tile(data, **kwargs):\n    if data[0, 0, 0] == 0:\n        os._exit(1)\n    return data[0]\n",
+            vec![tile_u8([0, 0], [2, 2], vec![0, 1, 2, 3])],
+        )
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        let query_processor = operator.query_processor().unwrap().get_u8().unwrap();
+
+        let result = query_processor
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(result.len(), 1);
+        assert!(result[0]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("PythonWorkerCrashed"));
+
+        // the next query gets a new worker process
+        let result = query_u8(subprocess_operator(
+            DOUBLE_SCRIPT,
+            vec![tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4])],
+        ))
+        .await;
+
+        assert_eq!(result, vec![tile_u8([0, 0], [2, 2], vec![2, 4, 6, 8])]);
+    }
+
+    #[test]
+    fn subprocess_rejects_operator_state() {
+        let mut operator = subprocess_operator(DOUBLE_SCRIPT, vec![]);
+        operator.params.options.state = PyStateScope::Operator;
+
+        let result = operator
+            .boxed()
+            .initialize(&MockExecutionContext::default());
+
+        assert!(result.is_err());
+    }
+
+    async fn query_error(operator: PyOperator) -> String {
+        let operator = operator
+            .boxed()
+            .initialize(&MockExecutionContext::default())
+            .unwrap();
+
+        let query_processor = operator.query_processor().unwrap().get_u8().unwrap();
+
+        let result = query_processor
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(result.len(), 1);
+
+        result[0].as_ref().unwrap_err().to_string()
+    }
+
+    #[tokio::test]
+    async fn memory_limit() {
+        let mut operator = subprocess_operator(
+            "def tile(data, **kwargs):\n    greedy = bytearray(1024 * 1024 * 1024)\n    return data[0]\n",
+            vec![tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4])],
+        );
+        operator.params.options.limits.memory_mb = Some(256);
+
+        let error = query_error(operator).await;
+
+        assert!(error.contains("ResourceLimitExceededError"));
+        assert!(error.contains("256 MB of memory"));
+    }
+
+    #[tokio::test]
+    async fn cpu_time_limit() {
+        let mut operator = subprocess_operator(
+            "def tile(data, **kwargs):\n    while True:\n        pass\n",
+            vec![tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4])],
+        );
+        operator.params.options.limits.cpu_time_ms = Some(200);
+
+        let error = query_error(operator).await;
+
+        assert!(error.contains("ResourceLimitExceededError"));
+        assert!(error.contains("200 ms of CPU time"));
+    }
+
+    #[tokio::test]
+    async fn dropped_queries_interrupt_the_worker() {
+        let marker = std::env::temp_dir().join(format!(
+            "geoengine-python-interrupted-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&marker);
+
+        let mut operator = subprocess_operator(
+            "import time\n\ndef tile(data, marker, **kwargs):\n    try:\n        time.sleep(60)\n    except KeyboardInterrupt:\n        open(marker, 'w').close()\n        raise\n    return data[0]\n",
+            vec![tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4])],
+        );
+        operator.params.options.parameters = serde_json::json!({ "marker": marker });
+
+        let operator = operator
+            .boxed()
+            .initialize(&MockExecutionContext::default())
+            .unwrap();
+        let query_processor = operator.query_processor().unwrap().get_u8().unwrap();
+        let ctx = MockQueryContext::new(0);
+
+        let mut tiles = query_processor.query(query_rectangle(), &ctx).unwrap();
+
+        // the hook sleeps, so the tile is still being computed when the query is dropped
+        let tile = tokio::time::timeout(Duration::from_secs(2), tiles.next()).await;
+        assert!(tile.is_err());
+        drop(tiles);
+
+        let mut interrupted = false;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            interrupted = marker.exists();
+            if interrupted {
+                break;
+            }
+        }
+
+        let _ = std::fs::remove_file(&marker);
+        assert!(interrupted);
+    }
+
+    #[test]
+    fn limits_require_the_subprocess_backend() {
+        let mut operator = subprocess_operator(DOUBLE_SCRIPT, vec![]);
+        operator.params.options.backend = Some(PyBackend::InProcess);
+        operator.params.options.limits.memory_mb = Some(256);
+
+        let result = operator
+            .boxed()
+            .initialize(&MockExecutionContext::default());
+
+        assert!(result.is_err());
     }
 }
@@ -0,0 +1,81 @@
+use geoengine_operators::engine::{
+    ExecutionContext, InitializedRasterOperator, RasterOperator, VectorOperator,
+};
+use geoengine_operators::util::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::example_pyop::{PyOperator, PyOperatorOptions, PyOperatorParams};
+use crate::plugin::plugin_registry;
+use crate::script::PyScript;
+
+/// An operator that runs the plugin script that declares the operator name, see `crate::plugin`
+///
+/// In workflows, it is used as `{"type": "Python", "params": {"operator": "ipca", …}}`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PyPluginOperator {
+    pub params: PyPluginOperatorParams,
+    pub raster_sources: Vec<Box<dyn RasterOperator>>,
+    #[serde(default)]
+    pub vector_sources: Vec<Box<dyn VectorOperator>>,
+}
+
+/// The parameter spec for `PyPluginOperator`, the plugin provides the script and its hooks
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PyPluginOperatorParams {
+    /// The name that the plugin declares
+    pub operator: String,
+    #[serde(flatten)]
+    pub options: PyOperatorOptions,
+}
+
+#[typetag::serde(name = "Python")]
+impl RasterOperator for PyPluginOperator {
+    fn initialize(
+        self: Box<Self>,
+        context: &dyn ExecutionContext,
+    ) -> Result<Box<InitializedRasterOperator>> {
+        let registry = plugin_registry()?;
+        let plugin = registry.get(&self.params.operator)?;
+
+        let params = self.params;
+        let operator = PyOperator {
+            params: PyOperatorParams {
+                script: PyScript::Plugin(params.operator),
+                module_name: plugin.module_name(),
+                hooks: plugin.declaration.hooks.clone(),
+                options: params.options,
+            },
+            raster_sources: self.raster_sources,
+            vector_sources: self.vector_sources,
+        };
+
+        operator.boxed().initialize(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn deserializes_workflows() {
+        let operator = serde_json::from_value::<Box<dyn RasterOperator>>(json!({
+            "type": "Python",
+            "params": {
+                "operator": "ipca",
+                "parameters": {"n_components": 5},
+                "timeout_ms": 100
+            },
+            "raster_sources": []
+        }))
+        .unwrap();
+
+        let operator = serde_json::to_value(&operator).unwrap();
+
+        assert_eq!(operator["type"], "Python");
+        assert_eq!(operator["params"]["operator"], "ipca");
+        assert_eq!(operator["params"]["parameters"], json!({"n_components": 5}));
+        assert_eq!(operator["params"]["timeout_ms"], 100);
+    }
+}
@@ -0,0 +1,489 @@
+use futures::stream::BoxStream;
+use geoengine_datatypes::collections::{FeatureCollection, VectorDataType};
+use geoengine_datatypes::primitives::{
+    FeatureDataType, Geometry, MultiLineString, MultiPoint, MultiPolygon, NoGeometry,
+};
+use geoengine_datatypes::raster::{Pixel, RasterTile2D};
+use geoengine_datatypes::util::arrow::ArrowTyped;
+use geoengine_operators::engine::{
+    ExecutionContext, InitializedOperator, InitializedOperatorBase, InitializedRasterOperator,
+    InitializedVectorOperator, QueryContext, QueryRectangle, RasterOperator, RasterQueryProcessor,
+    TypedVectorQueryProcessor, VectorOperator, VectorQueryProcessor, VectorResultDescriptor,
+};
+use geoengine_operators::error::Error as GeoengineOperatorsError;
+use geoengine_operators::util::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::convert::{check_kwargs, FeaturesFromPy, GeometryFromPy};
+use crate::error::Error;
+use crate::example_pyop::{PyOperatorModule, PyOperatorOptions, PyOperatorScript};
+use crate::reload::Reloadable;
+use crate::runner::PyRunner;
+use crate::script::{PyHooks, PyScript};
+use crate::subprocess::PyBackend;
+use crate::util::{call_on_raster_processors, initialize_sources};
+use crate::worker::configured_timeout;
+use pyo3::prelude::*;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// An operator that turns its input raster stream into features with the functions of a
+/// Python script
+///
+/// For every tile, the tile function returns a dict with a list of `geometries`, a dict of
+/// `columns` and an optional list of `time` intervals, i.e., the same layout in which vector
+/// inputs are handed to the script.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PyVectorOperator {
+    pub params: PyVectorOperatorParams,
+    pub raster_sources: Vec<Box<dyn RasterOperator>>,
+    pub vector_sources: Vec<Box<dyn VectorOperator>>,
+}
+
+/// The parameter spec for `PyVectorOperator`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PyVectorOperatorParams {
+    /// The Python script that implements the operator
+    pub script: PyScript,
+    /// The name under which the script is loaded as a Python module
+    pub module_name: String,
+    /// The functions of the script that are called during processing
+    pub hooks: PyHooks,
+    /// The options of `PyOperator`, except for the backend and the raster outputs, since the
+    /// features are always created in process
+    #[serde(flatten)]
+    pub options: PyOperatorOptions,
+    /// The geometry type of the returned features
+    pub output_type: VectorDataType,
+    /// The columns that the returned features have
+    #[serde(default)]
+    pub output_columns: HashMap<String, FeatureDataType>,
+}
+
+#[typetag::serde]
+impl VectorOperator for PyVectorOperator {
+    fn initialize(
+        self: Box<Self>,
+        context: &dyn ExecutionContext,
+    ) -> Result<Box<InitializedVectorOperator>> {
+        let (initialized_rasters, initialized_vectors) =
+            initialize_sources(self.raster_sources, self.vector_sources, context)?;
+
+        let result_descriptor = VectorResultDescriptor {
+            data_type: self.params.output_type,
+            spatial_reference: initialized_rasters[0].result_descriptor().spatial_reference,
+            columns: self.params.output_columns.clone(),
+        };
+
+        check_vector_options(&self.params.options)?;
+
+        check_kwargs(&self.params.options.parameters)?;
+
+        let timeout = configured_timeout(self.params.options.timeout_ms)?;
+
+        let params = &self.params;
+        let script = Reloadable::load(&params.module_name, || {
+            load_script(params, &initialized_vectors)
+        })?;
+
+        let initialized_operator = InitializedPyVectorOperator {
+            params: self.params,
+            script,
+            raster_sources: initialized_rasters,
+            vector_sources: initialized_vectors,
+            result_descriptor,
+            timeout,
+        };
+
+        Ok(initialized_operator.boxed())
+    }
+}
+
+/// Reject the options of `PyOperator` that do not apply to features
+fn check_vector_options(options: &PyOperatorOptions) -> Result<()> {
+    if options.backend == Some(PyBackend::Subprocess) {
+        return Err(Error::UnsupportedByBackend {
+            reason: "vector outputs are only supported in process".to_string(),
+        }
+        .into());
+    }
+
+    if options.output_data_type.is_some()
+        || options.output_measurement.is_some()
+        || options.output_no_data_value.is_some()
+    {
+        return Err(GeoengineOperatorsError::InvalidOperatorSpec {
+            reason: "the output data type, measurement and no-data value only apply to rasters"
+                .to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Load the script in process, regardless of the configured backend
+fn load_script(
+    params: &PyVectorOperatorParams,
+    vector_sources: &[Box<InitializedVectorOperator>],
+) -> Result<PyOperatorScript> {
+    PyOperatorScript::load(
+        &params.script,
+        &params.module_name,
+        &params.hooks,
+        &params.options,
+        PyBackend::InProcess,
+        vector_sources,
+    )
+}
+
+pub struct InitializedPyVectorOperator {
+    pub params: PyVectorOperatorParams,
+    pub script: Reloadable<PyOperatorScript>,
+    pub raster_sources: Vec<Box<InitializedRasterOperator>>,
+    pub vector_sources: Vec<Box<InitializedVectorOperator>>,
+    pub result_descriptor: VectorResultDescriptor,
+    pub timeout: Option<Duration>,
+}
+
+impl InitializedOperatorBase for InitializedPyVectorOperator {
+    type Descriptor = VectorResultDescriptor;
+
+    fn result_descriptor(&self) -> &Self::Descriptor {
+        &self.result_descriptor
+    }
+
+    fn raster_sources(&self) -> &[Box<InitializedRasterOperator>] {
+        &self.raster_sources
+    }
+
+    fn vector_sources(&self) -> &[Box<InitializedVectorOperator>] {
+        &self.vector_sources
+    }
+
+    fn raster_sources_mut(&mut self) -> &mut [Box<InitializedRasterOperator>] {
+        &mut self.raster_sources
+    }
+
+    fn vector_sources_mut(&mut self) -> &mut [Box<InitializedVectorOperator>] {
+        &mut self.vector_sources
+    }
+}
+
+impl InitializedOperator<VectorResultDescriptor, TypedVectorQueryProcessor>
+    for InitializedPyVectorOperator
+{
+    fn query_processor(&self) -> Result<TypedVectorQueryProcessor> {
+        let vectors = self
+            .vector_sources
+            .iter()
+            .map(|vector| vector.query_processor())
+            .collect::<Result<Vec<_>>>()?;
+
+        let script = self
+            .script
+            .current(|| load_script(&self.params, &self.vector_sources))?;
+
+        let processor = call_on_raster_processors!(self.raster_sources, rasters => {
+            match self.result_descriptor.data_type {
+                VectorDataType::Data => TypedVectorQueryProcessor::Data(
+                    PyVectorProcessor::<_, NoGeometry>::new(rasters, vectors, self, &script)?
+                        .boxed(),
+                ),
+                VectorDataType::MultiPoint => TypedVectorQueryProcessor::MultiPoint(
+                    PyVectorProcessor::<_, MultiPoint>::new(rasters, vectors, self, &script)?
+                        .boxed(),
+                ),
+                VectorDataType::MultiLineString => TypedVectorQueryProcessor::MultiLineString(
+                    PyVectorProcessor::<_, MultiLineString>::new(rasters, vectors, self, &script)?
+                        .boxed(),
+                ),
+                VectorDataType::MultiPolygon => TypedVectorQueryProcessor::MultiPolygon(
+                    PyVectorProcessor::<_, MultiPolygon>::new(rasters, vectors, self, &script)?
+                        .boxed(),
+                ),
+            }
+        });
+
+        Ok(processor)
+    }
+}
+
+pub struct PyVectorProcessor<TIn, G>
+where
+    TIn: Pixel,
+{
+    runner: PyRunner<TIn>,
+    columns: Arc<HashMap<String, FeatureDataType>>,
+    _output: PhantomData<G>,
+}
+
+impl<TIn, G> PyVectorProcessor<TIn, G>
+where
+    TIn: Pixel + numpy::Element,
+{
+    pub fn new(
+        rasters: Vec<Box<dyn RasterQueryProcessor<RasterType = TIn>>>,
+        vectors: Vec<TypedVectorQueryProcessor>,
+        operator: &InitializedPyVectorOperator,
+        script: &PyOperatorScript,
+    ) -> Result<Self> {
+        let module = match &script.module {
+            PyOperatorModule::InProcess(module) => Python::with_gil(|py| module.clone_ref(py)),
+            PyOperatorModule::Subprocess(_) => {
+                return Err(Error::UnsupportedByBackend {
+                    reason: "vector outputs are only supported in process".to_string(),
+                }
+                .into())
+            }
+        };
+
+        let runner = PyRunner::new(
+            rasters,
+            vectors,
+            module,
+            &script.parameters,
+            operator.result_descriptor.spatial_reference,
+        )?
+        .with_shared_state(script.state.clone())
+        .with_model(script.model.clone())
+        .with_batch_size(operator.params.options.batch_size)
+        .with_timeout(operator.timeout)
+        .with_fit_buffer(operator.params.options.fit_buffer_bytes);
+
+        Ok(Self {
+            runner,
+            columns: Arc::new(operator.result_descriptor.columns.clone()),
+            _output: PhantomData,
+        })
+    }
+}
+
+impl<TIn, G> VectorQueryProcessor for PyVectorProcessor<TIn, G>
+where
+    TIn: Pixel + numpy::Element,
+    G: Geometry + ArrowTyped + GeometryFromPy + Send + Sync + 'static,
+{
+    type VectorType = FeatureCollection<G>;
+
+    fn vector_query<'a>(
+        &'a self,
+        query: QueryRectangle,
+        ctx: &'a dyn QueryContext,
+    ) -> Result<BoxStream<'a, Result<Self::VectorType>>> {
+        let columns = self.columns.clone();
+
+        Ok(self.runner.query(
+            query,
+            ctx,
+            move |result: &PyAny, tiles: &[RasterTile2D<TIn>]| {
+                Ok(FeatureCollection::<G>::from_py(
+                    result,
+                    &columns,
+                    tiles[0].time,
+                )?)
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::PyStateScope;
+    use crate::test_util::{mock_raster_source, query_rectangle, timed_tile_u8};
+    use futures::StreamExt;
+    use geoengine_datatypes::collections::{MultiPointCollection, MultiPolygonCollection};
+    use geoengine_datatypes::primitives::{FeatureData, TimeInterval};
+    use geoengine_operators::engine::{MockExecutionContext, MockQueryContext, QueryProcessor};
+
+    fn inline_params(
+        source: &str,
+        output_type: VectorDataType,
+        output_columns: &[(&str, FeatureDataType)],
+    ) -> PyVectorOperatorParams {
+        PyVectorOperatorParams {
+            script: PyScript::Source(source.to_string()),
+            module_name: "inline".to_string(),
+            hooks: PyHooks {
+                tile: "tile".to_string(),
+                fit: None,
+                setup: None,
+                teardown: None,
+            },
+            options: PyOperatorOptions {
+                parameters: serde_json::Value::Null,
+                state: PyStateScope::Query,
+                model: None,
+                timeout_ms: None,
+                batch_size: None,
+                backend: None,
+                limits: Default::default(),
+                fit_buffer_bytes: None,
+                output_data_type: None,
+                output_measurement: None,
+                output_no_data_value: None,
+            },
+            output_type,
+            output_columns: output_columns
+                .iter()
+                .map(|(name, data_type)| (name.to_string(), *data_type))
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn points_from_tiles() {
+        let operator = PyVectorOperator {
+            params: inline_params(
+                "def tile(data, **kwargs):\n    return {'geometries': [[(0.5, 0.5)]], 'columns': {'sum': [float(data[0].sum())]}}\n",
+                VectorDataType::MultiPoint,
+                &[("sum", FeatureDataType::Number)],
+            ),
+            raster_sources: vec![mock_raster_source(vec![
+                timed_tile_u8(
+                    TimeInterval::new_unchecked(0, 10),
+                    [0, 0],
+                    [2, 2],
+                    vec![1, 2, 3, 4],
+                ),
+                timed_tile_u8(
+                    TimeInterval::new_unchecked(0, 10),
+                    [0, 1],
+                    [2, 2],
+                    vec![5, 6, 7, 8],
+                ),
+            ])],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        assert_eq!(
+            operator.result_descriptor().data_type,
+            VectorDataType::MultiPoint
+        );
+
+        let query_processor = operator.query_processor().unwrap().multi_point().unwrap();
+
+        let result = query_processor
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .map(|collection| collection.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        let expected = |sum: f64| {
+            MultiPointCollection::from_data(
+                vec![MultiPoint::new(vec![(0.5, 0.5).into()]).unwrap()],
+                vec![TimeInterval::new_unchecked(0, 10)],
+                [("sum".to_string(), FeatureData::Number(vec![sum]))]
+                    .iter()
+                    .cloned()
+                    .collect(),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(result, vec![expected(10.), expected(26.)]);
+    }
+
+    #[tokio::test]
+    async fn polygons_with_time() {
+        let operator = PyVectorOperator {
+            params: inline_params(
+                "def tile(data, **kwargs):\n    return {'geometries': [[[(0., 0.), (1., 0.), (1., 1.), (0., 0.)]]], 'columns': {}, 'time': [(3, 4)]}\n",
+                VectorDataType::MultiPolygon,
+                &[],
+            ),
+            raster_sources: vec![mock_raster_source(vec![timed_tile_u8(
+                TimeInterval::new_unchecked(0, 10),
+                [0, 0],
+                [2, 2],
+                vec![1, 2, 3, 4],
+            )])],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        let query_processor = operator.query_processor().unwrap().multi_polygon().unwrap();
+
+        let result = query_processor
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .map(|collection| collection.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        let expected = MultiPolygonCollection::from_data(
+            vec![MultiPolygon::new(vec![vec![vec![
+                (0., 0.).into(),
+                (1., 0.).into(),
+                (1., 1.).into(),
+                (0., 0.).into(),
+            ]]])
+            .unwrap()],
+            vec![TimeInterval::new_unchecked(3, 4)],
+            Default::default(),
+        )
+        .unwrap();
+
+        assert_eq!(result, vec![expected]);
+    }
+
+    #[tokio::test]
+    async fn missing_output_column() {
+        let operator = PyVectorOperator {
+            params: inline_params(
+                "def tile(data, **kwargs):\n    return {'geometries': [[(0.5, 0.5)]], 'columns': {}}\n",
+                VectorDataType::MultiPoint,
+                &[("label", FeatureDataType::Text)],
+            ),
+            raster_sources: vec![mock_raster_source(vec![timed_tile_u8(
+                TimeInterval::new_unchecked(0, 10),
+                [0, 0],
+                [2, 2],
+                vec![1, 2, 3, 4],
+            )])],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default())
+        .unwrap();
+
+        let query_processor = operator.query_processor().unwrap().multi_point().unwrap();
+
+        let result = query_processor
+            .query(query_rectangle(), &MockQueryContext::new(0))
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(result.len(), 1);
+        assert!(result[0].is_err());
+    }
+
+    #[test]
+    fn raster_options_are_rejected() {
+        let mut params = inline_params(
+            "def tile(data, **kwargs):\n    return {'geometries': [], 'columns': {}}\n",
+            VectorDataType::MultiPoint,
+            &[],
+        );
+        params.options.output_no_data_value = Some(0.);
+
+        let result = PyVectorOperator {
+            params,
+            raster_sources: vec![mock_raster_source(vec![])],
+            vector_sources: vec![],
+        }
+        .boxed()
+        .initialize(&MockExecutionContext::default());
+
+        assert!(result.is_err());
+    }
+}
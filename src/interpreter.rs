@@ -0,0 +1,205 @@
+use crate::config;
+use crate::error::{Error, PyResultExt, Result};
+use crate::plugin::plugin_registry;
+use crate::reload::watch_scripts;
+use crate::subprocess::{start_idle_worker, PyBackend};
+use geoengine_services::util::config::get_config_element;
+use lazy_static::lazy_static;
+use pyo3::exceptions::PyModuleNotFoundError;
+use pyo3::types::IntoPyDict;
+use pyo3::Python;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+lazy_static! {
+    static ref PREPARED: Mutex<bool> = Mutex::new(false);
+}
+
+/// Puts `paths` and the directories that `site.addsitedir` adds for `site_dirs` in front of
+/// `sys.path`, so they take precedence over the globally installed packages
+const EXTEND_SYS_PATH: &str = r#"
+import site, sys
+before = [path for path in sys.path if path not in paths]
+for site_dir in site_dirs:
+    site.addsitedir(site_dir)
+added = [path for path in sys.path if path not in before and path not in paths]
+sys.path[:] = paths + added + before
+"#;
+
+/// Prepare the Python operators when a service starts, and report a broken environment
+///
+/// The embedded interpreter is prepared, see `prepare_interpreter`. If the `subprocess` backend is
+/// configured, a first worker process is started with the same settings. The plugin scripts are
+/// scanned and fail the startup if any of them is invalid. Finally, the scripts are watched if
+/// `python.hot_reload` is enabled.
+pub fn initialize() -> Result<()> {
+    prepare_interpreter()?;
+
+    if get_config_element::<config::Python>()?.backend == PyBackend::Subprocess {
+        start_idle_worker()?;
+    }
+
+    plugin_registry()?.validate()?;
+
+    watch_scripts()
+}
+
+/// Apply the `[python]` settings to the embedded interpreter, unless that happened before
+///
+/// The `extra_sys_path`, the `script_root` and the virtualenv's packages are put in front of
+/// `sys.path` and the `preload_modules` are imported. A failed preparation is retried the next
+/// time. The worker processes of the `subprocess` backend apply the same settings when they
+/// start.
+pub fn prepare_interpreter() -> Result<()> {
+    // the flag stays valid even if a thread panicked while holding the lock
+    let mut prepared = PREPARED.lock().unwrap_or_else(PoisonError::into_inner);
+
+    if *prepared {
+        return Ok(());
+    }
+
+    let config = get_config_element::<config::Python>()?;
+
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+
+    let mut paths = config.extra_sys_path.clone();
+    paths.push(config.script_root.clone());
+
+    let site_dirs = match &config.virtualenv {
+        Some(virtualenv) => vec![virtualenv_site_packages(py, virtualenv)?],
+        None => vec![],
+    };
+
+    extend_sys_path(py, &paths, &site_dirs)?;
+    preload_modules(py, &config.preload_modules)?;
+
+    *prepared = true;
+
+    Ok(())
+}
+
+fn extend_sys_path(py: Python, paths: &[PathBuf], site_dirs: &[PathBuf]) -> Result<()> {
+    let to_strings = |paths: &[PathBuf]| {
+        paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+    };
+
+    // the comprehensions only see globals, so the arguments are passed as such
+    let globals = [
+        ("paths", to_strings(paths)),
+        ("site_dirs", to_strings(site_dirs)),
+    ]
+    .into_py_dict(py);
+
+    py.run(EXTEND_SYS_PATH, Some(globals), None).py_context(py)
+}
+
+/// The `site-packages` directory of `virtualenv` for the running Python version
+fn virtualenv_site_packages(py: Python, virtualenv: &Path) -> Result<PathBuf> {
+    let (major, minor) = py
+        .import("sys")
+        .and_then(|sys| sys.getattr("version_info"))
+        .and_then(|version| {
+            Ok((
+                version.getattr("major")?.extract::<u8>()?,
+                version.getattr("minor")?.extract::<u8>()?,
+            ))
+        })
+        .py_context(py)?;
+
+    site_packages_candidates(virtualenv, major, minor)
+        .into_iter()
+        .find(|path| path.is_dir())
+        .ok_or_else(|| Error::InvalidVirtualenv {
+            path: virtualenv.to_path_buf(),
+            python_version: format!("{}.{}", major, minor),
+        })
+}
+
+/// The locations of a virtualenv's `site-packages` on Unix and on Windows
+fn site_packages_candidates(virtualenv: &Path, major: u8, minor: u8) -> Vec<PathBuf> {
+    vec![
+        virtualenv
+            .join("lib")
+            .join(format!("python{}.{}", major, minor))
+            .join("site-packages"),
+        virtualenv.join("Lib").join("site-packages"),
+    ]
+}
+
+/// Import all `modules` and report every one that is not installed
+fn preload_modules(py: Python, modules: &[String]) -> Result<()> {
+    let mut missing = Vec::new();
+
+    for module in modules {
+        match py.import(module.as_str()) {
+            Ok(_) => {}
+            Err(error) if error.is_instance::<PyModuleNotFoundError>(py) => {
+                missing.push(module.clone())
+            }
+            Err(error) => return Err(Error::from_py_err(py, error)),
+        }
+    }
+
+    if !missing.is_empty() {
+        return Err(Error::MissingPythonModules { modules: missing });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_all_missing_modules() {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let modules = ["json", "no_such_module", "math", "no_such_module_either"]
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        match preload_modules(py, &modules) {
+            Err(Error::MissingPythonModules { modules }) => {
+                assert_eq!(modules, vec!["no_such_module", "no_such_module_either"])
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn puts_paths_in_front() {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let paths = [PathBuf::from("/first"), PathBuf::from("/second")];
+        extend_sys_path(py, &paths, &[]).unwrap();
+        // extending again does not duplicate the paths
+        extend_sys_path(py, &paths, &[]).unwrap();
+
+        let sys_path = py
+            .import("sys")
+            .unwrap()
+            .getattr("path")
+            .unwrap()
+            .extract::<Vec<String>>()
+            .unwrap();
+
+        assert_eq!(&sys_path[..2], &["/first", "/second"]);
+        assert_eq!(sys_path.iter().filter(|path| *path == "/first").count(), 1);
+    }
+
+    #[test]
+    fn finds_site_packages() {
+        assert_eq!(
+            site_packages_candidates(Path::new("venv"), 3, 8)[0],
+            PathBuf::from("venv/lib/python3.8/site-packages")
+        );
+    }
+}
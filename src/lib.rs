@@ -1,6 +1,25 @@
+pub mod config;
+pub mod convert;
 pub mod error;
 // pub mod example_operator;
 pub mod example_pyop;
+pub mod example_pypluginop;
+pub mod example_pyvectorop;
+pub mod interpreter;
+pub mod metadata;
+pub mod model;
+pub mod plugin;
+pub mod reload;
+pub mod runner;
+pub mod schema;
+pub mod script;
+pub mod sdk;
+pub mod state;
+pub mod subprocess;
+#[cfg(test)]
+mod test_util;
+pub mod util;
+pub mod worker;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,125 @@
+use crate::util::pixel_to_f64;
+use geoengine_datatypes::primitives::TimeInterval;
+use geoengine_datatypes::raster::{Pixel, RasterTile2D};
+use geoengine_datatypes::spatial_reference::SpatialReferenceOption;
+use geoengine_operators::engine::QueryRectangle;
+use serde::{Deserialize, Serialize};
+
+/// Where and when a tile is located, passed to the hooks as the keyword argument `metadata`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TileMetadata {
+    /// Start and end of the tile's validity in milliseconds since the Unix epoch
+    pub time: [i64; 2],
+    /// Position of the tile in the tile grid as `[row, column]`
+    pub tile_position: [isize; 2],
+    /// The tile's pixel to world transformation in the order of `affine.Affine(a, b, c, d, e, f)`,
+    /// as used by rasterio
+    pub geo_transform: [f64; 6],
+    /// The spatial reference of the tile, e.g., `EPSG:4326`
+    pub spatial_reference: String,
+    /// The no-data value of each band
+    pub no_data: Vec<Option<f64>>,
+    pub query: QueryMetadata,
+}
+
+/// The query that the tile belongs to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueryMetadata {
+    /// The queried area as `[min_x, min_y, max_x, max_y]`, i.e., in rasterio's bounds order
+    pub bbox: [f64; 4],
+    /// Start and end of the queried time interval in milliseconds since the Unix epoch
+    pub time: [i64; 2],
+    /// The queried pixel size as `[x, y]`
+    pub spatial_resolution: [f64; 2],
+}
+
+impl TileMetadata {
+    /// The metadata of aligned `tiles`, one per band
+    pub fn new<T: Pixel>(
+        tiles: &[RasterTile2D<T>],
+        spatial_reference: SpatialReferenceOption,
+        query: &QueryRectangle,
+    ) -> Self {
+        let tile = &tiles[0];
+        let geo_transform = tile.geo_transform();
+        let origin = geo_transform.origin_coordinate;
+
+        Self {
+            time: time_to_array(&tile.time),
+            tile_position: tile.tile_position.0,
+            geo_transform: [
+                geo_transform.x_pixel_size,
+                0.,
+                origin.x,
+                0.,
+                geo_transform.y_pixel_size,
+                origin.y,
+            ],
+            spatial_reference: spatial_reference.to_string(),
+            no_data: tiles
+                .iter()
+                .map(|tile| tile.grid_array.no_data_value.map(pixel_to_f64))
+                .collect(),
+            query: QueryMetadata {
+                bbox: [
+                    query.bbox.lower_left().x,
+                    query.bbox.lower_left().y,
+                    query.bbox.upper_right().x,
+                    query.bbox.upper_right().y,
+                ],
+                time: time_to_array(&query.time_interval),
+                spatial_resolution: [query.spatial_resolution.x, query.spatial_resolution.y],
+            },
+        }
+    }
+}
+
+fn time_to_array(time_interval: &TimeInterval) -> [i64; 2] {
+    [time_interval.start().inner(), time_interval.end().inner()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geoengine_datatypes::primitives::{BoundingBox2D, SpatialResolution};
+    use geoengine_datatypes::raster::{GeoTransform, Grid2D, TileInformation};
+    use geoengine_datatypes::spatial_reference::SpatialReference;
+
+    #[test]
+    fn describes_tiles_in_affine_order() {
+        let tile = RasterTile2D::new_with_tile_info(
+            TimeInterval::new(1_000, 2_000).unwrap(),
+            TileInformation {
+                global_geo_transform: GeoTransform::new((10., 50.).into(), 0.5, -0.5),
+                global_tile_position: [1, 2].into(),
+                tile_size_in_pixels: [4, 4].into(),
+            },
+            Grid2D::new([4, 4].into(), vec![0_u8; 16], Some(255)).unwrap(),
+        );
+
+        let query = QueryRectangle {
+            bbox: BoundingBox2D::new((10., 46.).into(), (16., 50.).into()).unwrap(),
+            time_interval: TimeInterval::new(0, 5_000).unwrap(),
+            spatial_resolution: SpatialResolution::new(0.5, 0.5).unwrap(),
+        };
+
+        let metadata = TileMetadata::new(&[tile], SpatialReference::epsg_4326().into(), &query);
+
+        assert_eq!(
+            metadata,
+            TileMetadata {
+                time: [1_000, 2_000],
+                tile_position: [1, 2],
+                // the tile starts 1 * 4 rows below and 2 * 4 columns right of the origin
+                geo_transform: [0.5, 0., 14., 0., -0.5, 48.],
+                spatial_reference: "EPSG:4326".to_string(),
+                no_data: vec![Some(255.)],
+                query: QueryMetadata {
+                    bbox: [10., 46., 16., 50.],
+                    time: [0, 5_000],
+                    spatial_resolution: [0.5, 0.5],
+                },
+            }
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use crate::config;
+use crate::error::{self, Error, PyResultExt, Result};
+use crate::script::LoadedScript;
+use geoengine_services::util::config::get_config_element;
+use pyo3::types::PyBytes;
+use pyo3::{PyObject, Python};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use std::path::{Path, PathBuf};
+
+/// What an operator does with the fitted state of its script
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PyModelMode {
+    /// Fit the state in every query and discard it afterwards
+    Fit,
+    /// Load a previously saved state and skip the setup and fit hooks
+    Load,
+    /// Fit the state and save it after the training phase
+    FitAndSave,
+}
+
+/// The model of a Python operator, i.e., the persisted state of its script
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PyModelParams {
+    pub mode: PyModelMode,
+    /// Name of the model, the models of one name are distinguished by script and parameters
+    pub id: String,
+}
+
+/// A configured model with the pickled state if it is loaded
+pub struct PyModel {
+    pub mode: PyModelMode,
+    path: PathBuf,
+    pickled: Option<Vec<u8>>,
+}
+
+impl PyModel {
+    /// Locate the model in the configured `python.model_root` and read it if it is to be loaded
+    pub fn new(
+        params: &PyModelParams,
+        script: &LoadedScript,
+        parameters: &serde_json::Value,
+    ) -> Result<Self> {
+        let model_root = get_config_element::<config::Python>()?.model_root;
+        let path = model_path(&model_root, &params.id, script, parameters)?;
+
+        let pickled = match params.mode {
+            PyModelMode::Load => {
+                Some(std::fs::read(&path).context(error::CannotReadModel { path: path.clone() })?)
+            }
+            PyModelMode::Fit | PyModelMode::FitAndSave => None,
+        };
+
+        Ok(Self {
+            mode: params.mode,
+            path,
+            pickled,
+        })
+    }
+
+    /// Whether the script's fit hook is called
+    pub fn fits(&self) -> bool {
+        self.mode != PyModelMode::Load
+    }
+
+    /// Whether the fitted state is written to the model store
+    pub fn saves(&self) -> bool {
+        self.mode == PyModelMode::FitAndSave
+    }
+
+    /// The file of the model in the model store
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Unpickle the loaded state
+    pub fn load_state(&self, py: Python) -> Option<Result<PyObject>> {
+        self.pickled.as_ref().map(|pickled| {
+            py.import("pickle")
+                .and_then(|pickle| pickle.call1("loads", (PyBytes::new(py, pickled),)))
+                .map(Into::into)
+                .py_context(py)
+        })
+    }
+
+    /// Pickle `state` and write it to the model store if the model is to be saved
+    pub fn save_state(&self, py: Python, state: &PyObject) -> Result<()> {
+        if !self.saves() {
+            return Ok(());
+        }
+
+        let pickled = py
+            .import("pickle")
+            .and_then(|pickle| pickle.call1("dumps", (state,)))
+            .and_then(|pickled| Ok(pickled.downcast::<PyBytes>()?.as_bytes().to_vec()))
+            .py_context(py)?;
+
+        if let Some(directory) = self.path.parent() {
+            std::fs::create_dir_all(directory).context(error::CannotWriteModel {
+                path: self.path.clone(),
+            })?;
+        }
+
+        std::fs::write(&self.path, pickled).context(error::CannotWriteModel {
+            path: self.path.clone(),
+        })
+    }
+}
+
+/// The file of model `id` for the script and its parameters
+fn model_path(
+    model_root: &Path,
+    id: &str,
+    script: &LoadedScript,
+    parameters: &serde_json::Value,
+) -> Result<PathBuf> {
+    let is_valid_id = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !is_valid_id {
+        return Err(Error::InvalidModelId { id: id.to_string() });
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(Sha256::digest(script.code.as_bytes()));
+    hasher.update(script.module_name.as_bytes());
+    hasher.update(parameters.to_string().as_bytes());
+
+    Ok(model_root
+        .join(id)
+        .join(format!("{:x}.pickle", hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn script(code: &str) -> LoadedScript {
+        LoadedScript {
+            code: code.to_string(),
+            file_name: "model.py".to_string(),
+            module_name: "model".to_string(),
+        }
+    }
+
+    #[test]
+    fn models_are_keyed_by_script_and_parameters() {
+        let root = Path::new("models");
+        let path = model_path(root, "ipca", &script("x = 1"), &json!({"n": 5})).unwrap();
+
+        assert!(path.starts_with("models/ipca"));
+        assert_eq!(
+            path,
+            model_path(root, "ipca", &script("x = 1"), &json!({"n": 5})).unwrap()
+        );
+        assert_ne!(
+            path,
+            model_path(root, "ipca", &script("x = 2"), &json!({"n": 5})).unwrap()
+        );
+        assert_ne!(
+            path,
+            model_path(root, "ipca", &script("x = 1"), &json!({"n": 6})).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        let root = Path::new("models");
+
+        assert!(model_path(root, "../ipca", &script(""), &json!(null)).is_err());
+        assert!(model_path(root, "", &script(""), &json!(null)).is_err());
+    }
+
+    #[test]
+    fn deserializes_modes() {
+        assert_eq!(
+            serde_json::from_value::<PyModelParams>(json!({"mode": "fit_and_save", "id": "ipca"}))
+                .unwrap(),
+            PyModelParams {
+                mode: PyModelMode::FitAndSave,
+                id: "ipca".to_string(),
+            }
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use crate::config;
+use crate::error::{self, Error, Result};
+use crate::schema::PyParameterSchema;
+use crate::script::{read_declaration, PyHooks};
+use geoengine_services::util::config::get_config_element;
+use lazy_static::lazy_static;
+use pyo3::Python;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// The package that the modules of the plugin scripts are named after, so a plugin name like
+/// `json` cannot be mistaken for an installed module
+const PLUGIN_PACKAGE: &str = "geoengine_plugins";
+
+/// What a plugin script declares in its module level `OPERATOR` dict, which must be a literal
+///
+/// ```python
+/// OPERATOR = {
+///     'name': 'ipca',
+///     'hooks': {'tile': 'apply_ipca', 'fit': 'partial_fit_ipca', 'setup': 'setup'},
+///     'parameters': {'n_components': {'type': 'int', 'default': 5}},
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PyPluginDeclaration {
+    /// The name under which workflows use the operator, a valid Python identifier
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub hooks: PyHooks,
+    #[serde(default)]
+    pub parameters: PyParameterSchema,
+}
+
+/// A registered plugin script
+#[derive(Debug, Clone, PartialEq)]
+pub struct PyPlugin {
+    pub path: PathBuf,
+    pub declaration: PyPluginDeclaration,
+}
+
+impl PyPlugin {
+    /// The name of the module that the script is loaded as, within the `geoengine_plugins`
+    /// namespace
+    pub fn module_name(&self) -> String {
+        format!("{}.{}", PLUGIN_PACKAGE, self.declaration.name)
+    }
+}
+
+/// A plugin script that could not be registered
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidPyPlugin {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// The Python operators that the scripts of a plugin directory declare
+///
+/// Every `*.py` file with an `OPERATOR` declaration is a plugin, other files, e.g., helper
+/// modules, are skipped.
+#[derive(Debug, Default)]
+pub struct PyPluginRegistry {
+    plugins: BTreeMap<String, PyPlugin>,
+    invalid: Vec<InvalidPyPlugin>,
+}
+
+impl PyPluginRegistry {
+    /// Register the plugins in `plugin_root`, invalid ones are kept aside, see `validate`
+    pub fn scan(plugin_root: &Path) -> Result<Self> {
+        let mut paths = std::fs::read_dir(plugin_root)
+            .context(error::CannotReadPlugins {
+                path: plugin_root.to_path_buf(),
+            })?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && path.extension() == Some("py".as_ref()))
+            .collect::<Vec<_>>();
+
+        // of two plugins with the same name, the first file in alphabetical order wins
+        paths.sort();
+
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let mut registry = Self::default();
+
+        for path in paths {
+            match read_plugin_declaration(py, &path) {
+                Ok(Some(declaration)) => registry.register(path, declaration),
+                Ok(None) => {}
+                Err(error) => registry.invalid.push(InvalidPyPlugin {
+                    path,
+                    reason: error.to_string(),
+                }),
+            }
+        }
+
+        Ok(registry)
+    }
+
+    fn register(&mut self, path: PathBuf, declaration: PyPluginDeclaration) {
+        let reason = if !is_identifier(&declaration.name) {
+            Some(format!(
+                "\"{}\" is no valid Python identifier",
+                declaration.name
+            ))
+        } else {
+            self.plugins.get(&declaration.name).map(|plugin| {
+                format!(
+                    "\"{}\" is already declared by \"{}\"",
+                    declaration.name,
+                    plugin.path.display()
+                )
+            })
+        };
+
+        match reason {
+            Some(reason) => self.invalid.push(InvalidPyPlugin { path, reason }),
+            None => {
+                self.plugins
+                    .insert(declaration.name.clone(), PyPlugin { path, declaration });
+            }
+        }
+    }
+
+    /// The plugin that declares `name`
+    pub fn get(&self, name: &str) -> Result<&PyPlugin> {
+        self.plugins
+            .get(name)
+            .ok_or_else(|| Error::UnknownPythonOperator {
+                name: name.to_string(),
+                available: self.plugins.keys().cloned().collect(),
+            })
+    }
+
+    /// All registered plugins, ordered by name
+    pub fn plugins(&self) -> impl Iterator<Item = &PyPlugin> {
+        self.plugins.values()
+    }
+
+    /// The plugin scripts that could not be registered
+    pub fn invalid(&self) -> &[InvalidPyPlugin] {
+        &self.invalid
+    }
+
+    /// Fail with every plugin script that could not be registered
+    pub fn validate(&self) -> Result<()> {
+        if self.invalid.is_empty() {
+            return Ok(());
+        }
+
+        Err(Error::InvalidPythonPlugins {
+            plugins: self
+                .invalid
+                .iter()
+                .map(|plugin| format!("\"{}\": {}", plugin.path.display(), plugin.reason))
+                .collect(),
+        })
+    }
+}
+
+fn read_plugin_declaration(py: Python, path: &Path) -> Result<Option<PyPluginDeclaration>> {
+    let code = std::fs::read_to_string(path).context(error::CannotReadScript {
+        path: path.to_path_buf(),
+    })?;
+
+    let declaration = read_declaration(py, &code, &path.to_string_lossy(), "OPERATOR")?;
+
+    declaration
+        .map(|declaration| {
+            serde_json::from_value(declaration).map_err(|error| {
+                Error::InvalidPythonPluginDeclaration {
+                    reason: error.to_string(),
+                }
+            })
+        })
+        .transpose()
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<Option<Arc<PyPluginRegistry>>> = RwLock::new(None);
+}
+
+/// The registry of the configured `python.plugin_root`, which is scanned on first use
+pub fn plugin_registry() -> Result<Arc<PyPluginRegistry>> {
+    // the registry is replaced as a whole, so a poisoned lock still holds a valid one
+    if let Some(registry) = REGISTRY
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+    {
+        return Ok(registry.clone());
+    }
+
+    let mut registry = REGISTRY.write().unwrap_or_else(PoisonError::into_inner);
+
+    if let Some(registry) = registry.as_ref() {
+        return Ok(registry.clone());
+    }
+
+    let plugin_root = get_config_element::<config::Python>()?.plugin_root;
+    let scanned = Arc::new(PyPluginRegistry::scan(&plugin_root)?);
+    *registry = Some(scanned.clone());
+
+    Ok(scanned)
+}
+
+/// Forget the scanned registry, so the next `plugin_registry` scans the `plugin_root` again
+pub fn reset_plugin_registry() {
+    *REGISTRY.write().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_declared_operators() {
+        let plugin_root =
+            std::env::temp_dir().join(format!("geoengine-python-plugins-{}", std::process::id()));
+        std::fs::create_dir_all(&plugin_root).unwrap();
+
+        let plugins = [
+            (
+                "a_double.py",
+                "OPERATOR = {'name': 'double', 'hooks': {'tile': 'tile'}, 'parameters': {'factor': {'type': 'int', 'default': 2}}}\n\ndef tile(data, **kwargs):\n    return data[0] * 2\n",
+            ),
+            ("helpers.py", "def helper():\n    pass\n"),
+            (
+                "b_double.py",
+                "OPERATOR = {'name': 'double', 'hooks': {'tile': 'tile'}}\n",
+            ),
+            ("broken.py", "OPERATOR = {'name': 'broken'\n"),
+            (
+                "dynamic.py",
+                "OPERATOR = {'name': 'dyn' + 'amic', 'hooks': {'tile': 'tile'}}\n",
+            ),
+            ("typo.py", "OPERATOR = {'name': 'typo', 'hook': {'tile': 'tile'}}\n"),
+        ];
+        for (file_name, code) in &plugins {
+            std::fs::write(plugin_root.join(file_name), code).unwrap();
+        }
+
+        let registry = PyPluginRegistry::scan(&plugin_root).unwrap();
+        std::fs::remove_dir_all(&plugin_root).unwrap();
+
+        let names = registry
+            .plugins()
+            .map(|plugin| plugin.declaration.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["double"]);
+
+        let double = registry.get("double").unwrap();
+        assert_eq!(double.path, plugin_root.join("a_double.py"));
+        assert_eq!(double.declaration.hooks.tile, "tile");
+        assert!(double.declaration.parameters.contains_key("factor"));
+        assert_eq!(double.module_name(), "geoengine_plugins.double");
+
+        assert!(registry.get("triple").is_err());
+
+        let invalid = registry
+            .invalid()
+            .iter()
+            .map(|plugin| plugin.path.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            invalid,
+            vec!["b_double.py", "broken.py", "dynamic.py", "typo.py"]
+        );
+        assert!(registry.validate().is_err());
+    }
+
+    #[test]
+    fn checks_identifiers() {
+        assert!(is_identifier("ipca"));
+        assert!(is_identifier("_simple_pca2"));
+        assert!(!is_identifier("2pca"));
+        assert!(!is_identifier("simple-pca"));
+        assert!(!is_identifier(""));
+    }
+}
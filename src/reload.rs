@@ -0,0 +1,248 @@
+use crate::config;
+use crate::error::{self, PyResultExt, Result};
+use crate::plugin::{plugin_registry, reset_plugin_registry};
+use crate::subprocess::discard_idle_workers;
+use geoengine_services::util::config::get_config_element;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use pyo3::types::IntoPyDict;
+use pyo3::Python;
+use snafu::ResultExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+
+lazy_static! {
+    static ref WATCHING: Mutex<bool> = Mutex::new(false);
+}
+
+/// Counts the changes of the watched scripts, see `script_generation`
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Removes the modules that were imported from the watched directories from `sys.modules`, so
+/// the scripts import their helper modules again
+const FORGET_MODULES: &str = r#"
+import importlib, os, sys
+prefixes = tuple(os.path.join(os.path.abspath(root), '') for root in roots)
+for name, module in list(sys.modules.items()):
+    file = getattr(module, '__file__', None)
+    if file and os.path.abspath(file).startswith(prefixes):
+        del sys.modules[name]
+importlib.invalidate_caches()
+"#;
+
+/// Watch the `script_root` and the `plugin_root` if `python.hot_reload` is enabled, unless that
+/// happened before
+///
+/// This is a development mode: whenever a Python file in these directories changes, the cached
+/// modules are invalidated, so the next `query_processor()` of an initialized Python operator
+/// loads its script again. Reloads and syntax errors are logged.
+pub fn watch_scripts() -> Result<()> {
+    // the flag stays valid even if a thread panicked while holding the lock
+    let mut watching = WATCHING.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let config = get_config_element::<config::Python>()?;
+
+    if *watching || !config.hot_reload {
+        return Ok(());
+    }
+
+    let mut roots = vec![config.script_root, config.plugin_root];
+    // both default to the same directory
+    roots.dedup();
+
+    let (sender, receiver) = channel();
+    let mut watcher: RecommendedWatcher =
+        Watcher::new(sender, Duration::from_millis(200)).context(error::CannotWatchScripts)?;
+
+    for root in &roots {
+        watcher
+            .watch(root, RecursiveMode::Recursive)
+            .context(error::CannotWatchScripts)?;
+        info!("watching Python scripts in \"{}\"", root.display());
+    }
+
+    std::thread::spawn(move || {
+        // the watcher stops when it is dropped
+        let _watcher = watcher;
+
+        for event in receiver {
+            match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Rename(_, path)
+                    if is_python_file(&path) =>
+                {
+                    reload(&roots);
+                    info!("Python script \"{}\" changed", path.display());
+                    check_syntax(&path);
+                }
+                DebouncedEvent::Remove(path) if is_python_file(&path) => {
+                    reload(&roots);
+                    info!("removed Python script \"{}\"", path.display());
+                }
+                DebouncedEvent::Error(error, path) => {
+                    warn!("cannot watch Python scripts ({:?}): {}", path, error);
+                }
+                _ => {}
+            }
+        }
+    });
+
+    *watching = true;
+
+    Ok(())
+}
+
+/// The number of script changes so far, which never changes without `python.hot_reload`
+pub fn script_generation() -> u64 {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+/// Invalidate everything that was derived from the scripts in `roots`
+pub(crate) fn reload(roots: &[PathBuf]) {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+
+    reset_plugin_registry();
+    discard_idle_workers();
+
+    if let Err(error) = forget_modules(roots) {
+        warn!("cannot unload the modules of the Python scripts: {}", error);
+    }
+
+    // scanning right away reports broken plugins while they are edited
+    if let Err(error) = plugin_registry().and_then(|registry| registry.validate()) {
+        error!("{}", error);
+    }
+}
+
+fn forget_modules(roots: &[PathBuf]) -> Result<()> {
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+
+    let roots = roots
+        .iter()
+        .map(|root| root.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+
+    // the comprehension only sees globals, so the argument is passed as such
+    let globals = [("roots", roots)].into_py_dict(py);
+
+    py.run(FORGET_MODULES, Some(globals), None).py_context(py)
+}
+
+/// Log the syntax errors of the script at `path`, without running it
+fn check_syntax(path: &Path) {
+    let code = match std::fs::read_to_string(path) {
+        Ok(code) => code,
+        // the file may be gone already, e.g., if an editor replaced it
+        Err(_) => return,
+    };
+
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+
+    let result = py
+        .import("builtins")
+        .and_then(|builtins| {
+            builtins.call1("compile", (code, path.to_string_lossy().as_ref(), "exec"))
+        })
+        .py_context(py);
+
+    if let Err(error) = result {
+        error!("Python script \"{}\" is invalid: {}", path.display(), error);
+    }
+}
+
+fn is_python_file(path: &Path) -> bool {
+    path.extension() == Some("py".as_ref())
+}
+
+/// What an initialized operator derives from its script, which is loaded again by `current`
+/// after the script changed
+pub struct Reloadable<T> {
+    loaded: RwLock<(u64, Arc<T>)>,
+    /// The module of the script, for the log
+    module_name: String,
+}
+
+impl<T> Reloadable<T> {
+    pub fn load<E>(module_name: &str, load: impl FnOnce() -> Result<T, E>) -> Result<Self, E> {
+        Self::load_at(script_generation(), module_name, load)
+    }
+
+    /// The loaded value, which is replaced by the result of `load` if the scripts changed since
+    pub fn current<E>(&self, load: impl FnOnce() -> Result<T, E>) -> Result<Arc<T>, E> {
+        self.current_at(script_generation(), load)
+    }
+
+    // a change while loading makes the next `current` load the script again, because the
+    // generation is read before
+    fn load_at<E>(
+        generation: u64,
+        module_name: &str,
+        load: impl FnOnce() -> Result<T, E>,
+    ) -> Result<Self, E> {
+        Ok(Self {
+            loaded: RwLock::new((generation, Arc::new(load()?))),
+            module_name: module_name.to_string(),
+        })
+    }
+
+    fn current_at<E>(
+        &self,
+        generation: u64,
+        load: impl FnOnce() -> Result<T, E>,
+    ) -> Result<Arc<T>, E> {
+        // the value is replaced as a whole, so a poisoned lock still holds a valid one
+        {
+            let (loaded_generation, loaded) =
+                &*self.loaded.read().unwrap_or_else(PoisonError::into_inner);
+
+            if *loaded_generation == generation {
+                return Ok(loaded.clone());
+            }
+        }
+
+        let reloaded = Arc::new(load()?);
+
+        *self.loaded.write().unwrap_or_else(PoisonError::into_inner) =
+            (generation, reloaded.clone());
+
+        info!(
+            "reloaded the Python script of module \"{}\"",
+            self.module_name
+        );
+
+        Ok(reloaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_again_after_changes() {
+        let reloadable = Reloadable::load_at(0, "test", || Ok::<_, ()>(1)).unwrap();
+
+        assert_eq!(*reloadable.current_at(0, || Ok::<_, ()>(2)).unwrap(), 1);
+        assert_eq!(*reloadable.current_at(1, || Ok::<_, ()>(2)).unwrap(), 2);
+        assert_eq!(*reloadable.current_at(1, || Ok::<_, ()>(3)).unwrap(), 2);
+
+        // a failed reload keeps the previous value for the next try
+        assert!(reloadable.current_at(2, || Err(())).is_err());
+        assert_eq!(*reloadable.current_at(1, || Ok::<_, ()>(3)).unwrap(), 2);
+        assert_eq!(*reloadable.current_at(2, || Ok::<_, ()>(4)).unwrap(), 4);
+    }
+
+    #[test]
+    fn watches_python_files() {
+        assert!(is_python_file(Path::new("scripts/ipca.py")));
+        assert!(!is_python_file(Path::new("scripts/ipca.py.swp")));
+        assert!(!is_python_file(Path::new("scripts")));
+    }
+}
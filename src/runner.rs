@@ -0,0 +1,609 @@
+use crate::convert::{feature_collections_to_py, json_to_kwargs, json_to_py, tiles_into_py};
+use crate::error::{Error, PyResultExt};
+use crate::metadata::TileMetadata;
+use crate::model::PyModel;
+use crate::script::PyScriptModule;
+use crate::state::PyState;
+use crate::util::{query_feature_collections, zip_tile_streams, FitBuffer};
+use crate::worker::{run_python, run_python_with_timeout};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use geoengine_datatypes::raster::{Pixel, RasterTile2D};
+use geoengine_datatypes::spatial_reference::SpatialReferenceOption;
+use geoengine_operators::engine::{
+    QueryContext, QueryRectangle, RasterQueryProcessor, TypedVectorQueryProcessor,
+};
+use geoengine_operators::util::Result;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Turns the results of the tile hook into the items of a query, e.g., raster tiles
+///
+/// Any function from the result and the input tiles of a call is an output. Outputs may also
+/// provide a buffer that the tile hook gets as its `out` argument to write its result into, which
+/// saves copying it.
+pub trait PyOutput<TIn>: Send + Sync + 'static {
+    type Item: Send + 'static;
+    type Buffer;
+
+    /// The buffer for the result of the tile hook for `tiles` and the array that it is passed as
+    fn buffer<'py>(
+        &self,
+        py: Python<'py>,
+        tiles: &[RasterTile2D<TIn>],
+    ) -> PyResult<Option<(Self::Buffer, &'py PyAny)>>;
+
+    /// Convert the `result` of the tile hook for `tiles`, which may have been written to `buffer`
+    fn output(
+        &self,
+        py: Python,
+        result: PyObject,
+        buffer: Option<Self::Buffer>,
+        tiles: &[RasterTile2D<TIn>],
+    ) -> Result<Self::Item>;
+}
+
+impl<TIn, T, F> PyOutput<TIn> for F
+where
+    T: Send + 'static,
+    F: Fn(&PyAny, &[RasterTile2D<TIn>]) -> Result<T> + Send + Sync + 'static,
+{
+    type Item = T;
+    type Buffer = ();
+
+    fn buffer<'py>(
+        &self,
+        _py: Python<'py>,
+        _tiles: &[RasterTile2D<TIn>],
+    ) -> PyResult<Option<((), &'py PyAny)>> {
+        Ok(None)
+    }
+
+    fn output(
+        &self,
+        py: Python,
+        result: PyObject,
+        _buffer: Option<()>,
+        tiles: &[RasterTile2D<TIn>],
+    ) -> Result<T> {
+        self(result.as_ref(py), tiles)
+    }
+}
+
+/// Drives the hooks of a Python script over the aligned tiles of the raster inputs.
+///
+/// The runner is independent of what the operator produces: the result of the tile hook is
+/// handed to an output function that turns it into a raster tile, a feature collection, etc.
+/// All Python code runs on the Python worker pool, so the query streams stay asynchronous.
+///
+/// Besides the tile data, every call gets the `metadata` of its tile as a keyword argument. With a
+/// batch size, the hooks get a list of up to that many tiles and their metadata per call instead
+/// of a single tile, and the tile hook returns a sequence with one result per tile.
+pub struct PyRunner<TIn>
+where
+    TIn: Pixel,
+{
+    rasters: Vec<Box<dyn RasterQueryProcessor<RasterType = TIn>>>,
+    vectors: Vec<TypedVectorQueryProcessor>,
+    module: Arc<PyScriptModule>,
+    kwargs: Arc<Py<PyDict>>,
+    spatial_reference: SpatialReferenceOption,
+    shared_state: Option<Arc<PyState>>,
+    model: Option<Arc<PyModel>>,
+    batch_size: Option<NonZeroUsize>,
+    timeout: Option<Duration>,
+    fit_buffer_bytes: Option<usize>,
+}
+
+impl<TIn> PyRunner<TIn>
+where
+    TIn: Pixel + numpy::Element,
+{
+    pub fn new(
+        rasters: Vec<Box<dyn RasterQueryProcessor<RasterType = TIn>>>,
+        vectors: Vec<TypedVectorQueryProcessor>,
+        module: PyScriptModule,
+        parameters: &serde_json::Value,
+        spatial_reference: SpatialReferenceOption,
+    ) -> Result<Self> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let kwargs = json_to_kwargs(py, parameters).py_context(py)?;
+
+        Ok(Self {
+            rasters,
+            vectors,
+            module: Arc::new(module),
+            kwargs: Arc::new(kwargs.into()),
+            spatial_reference,
+            shared_state: None,
+            model: None,
+            batch_size: None,
+            timeout: None,
+            fit_buffer_bytes: None,
+        })
+    }
+
+    /// Use a state that is shared with other runners instead of creating one per query
+    pub fn with_shared_state(mut self, shared_state: Option<Arc<PyState>>) -> Self {
+        self.shared_state = shared_state;
+        self
+    }
+
+    /// Load or save the fitted state of the script
+    pub fn with_model(mut self, model: Option<Arc<PyModel>>) -> Self {
+        self.model = model;
+        self
+    }
+
+    /// Pass up to `batch_size` tiles to the hooks in one call
+    pub fn with_batch_size(mut self, batch_size: Option<NonZeroUsize>) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Interrupt calls of the fit and tile hooks that take longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Keep up to `bytes` of fitted tiles for the transform phase, see `FitBuffer`
+    pub fn with_fit_buffer(mut self, bytes: Option<usize>) -> Self {
+        self.fit_buffer_bytes = bytes;
+        self
+    }
+
+    /// Run the script on all tiles of the query and convert each result with `output`
+    ///
+    /// The pixel data of the input tiles is handed to Python without copying it, so `output`
+    /// only gets their metadata. Without batches, the tile hook gets the buffer of `output`, if
+    /// any, as its `out` argument and the result needs no copy if it was written there.
+    ///
+    /// If the script has a fit hook, all tiles of the query are first passed to it in a training
+    /// phase that yields nothing. Only after the last tile was fitted, the tiles are passed to
+    /// the tile hook, so every input tile leads to exactly one output.
+    ///
+    /// The transform phase gets the fitted tiles from the fit buffer if they fit into it. By
+    /// default, nothing is buffered, because a query may cover more tiles than fit into memory,
+    /// and the sources are queried again. This relies on them being deterministic, i.e., a query
+    /// of the same rectangle returns the same tiles in the same order both times.
+    ///
+    /// Without a shared state, the query creates its own state, which is torn down when the
+    /// returned stream is dropped. A model that is loaded replaces the training phase, a model
+    /// that is saved is written after it.
+    ///
+    /// Dropping the stream also interrupts the running Python call, and so does the timeout.
+    pub fn query<'a, O>(
+        &'a self,
+        query: QueryRectangle,
+        ctx: &'a dyn QueryContext,
+        output: O,
+    ) -> BoxStream<'a, Result<O::Item>>
+    where
+        O: PyOutput<TIn>,
+    {
+        futures::stream::once(async move {
+            // the vector inputs are queried once before the first tile is processed
+            let (kwargs, state) = self.query_kwargs(query, ctx).await?;
+
+            let fits = self.model.as_ref().map_or(true, |model| model.fits());
+
+            let fitted = match self.module.hooks.fit.as_ref().filter(|_| fits) {
+                Some(fit) => self.fit(fit, query, ctx, &kwargs).await?,
+                None => None,
+            };
+
+            if let Some(model) = self.model.as_ref().filter(|model| model.saves()) {
+                let model = model.clone();
+                let state = state.clone();
+
+                run_python(move || {
+                    Python::with_gil(|py| model.save_state(py, &state.object(py)))?;
+                    Ok(())
+                })
+                .await?;
+            }
+
+            let batches = match fitted {
+                Some(tiles) => {
+                    let batch_size = self.batch_size.map_or(1, NonZeroUsize::get);
+                    futures::stream::iter(tiles)
+                        .chunks(batch_size)
+                        .map(Ok)
+                        .boxed()
+                }
+                None => self.query_batches(query, ctx)?,
+            };
+
+            self.transform(query, batches, kwargs, state, output)
+        })
+        .map(|tiles| match tiles {
+            Ok(tiles) => tiles,
+            Err(error) => futures::stream::once(async { Err(error) }).boxed(),
+        })
+        .flatten()
+        .boxed()
+    }
+
+    /// The training phase: pass all tiles of the query to the fit hook and return the buffered
+    /// ones, if they all fit into the fit buffer
+    async fn fit(
+        &self,
+        fit: &str,
+        query: QueryRectangle,
+        ctx: &dyn QueryContext,
+        kwargs: &Arc<Py<PyDict>>,
+    ) -> Result<Option<Vec<Vec<RasterTile2D<TIn>>>>> {
+        let batched = self.batch_size.is_some();
+        let mut buffer = FitBuffer::new(self.fit_buffer_bytes);
+
+        self.query_batches(query, ctx)?
+            .try_for_each(|mut batch| {
+                for tiles in &batch {
+                    buffer.push(tiles);
+                }
+
+                let module = self.module.clone();
+                let kwargs = kwargs.clone();
+                let fit = fit.to_string();
+                let metadata = self.metadata(&batch, &query);
+
+                run_python_with_timeout(self.timeout, move || {
+                    fit_tiles(&module, &fit, &mut batch, batched, &kwargs, &metadata)
+                })
+            })
+            .await?;
+
+        Ok(buffer.into_tiles())
+    }
+
+    /// The transform phase: pass all `batches` of the query to the tile hook and convert the
+    /// results
+    fn transform<'a, O>(
+        &'a self,
+        query: QueryRectangle,
+        batches: BoxStream<'a, Result<Vec<Vec<RasterTile2D<TIn>>>>>,
+        kwargs: Arc<Py<PyDict>>,
+        state: Arc<PyState>,
+        output: O,
+    ) -> Result<BoxStream<'a, Result<O::Item>>>
+    where
+        O: PyOutput<TIn>,
+    {
+        let output = Arc::new(output);
+        let batched = self.batch_size.is_some();
+
+        let results = batches.then(move |batch| {
+            // the stream owns the state, so it lives until the query is done
+            let _state = &state;
+
+            let module = self.module.clone();
+            let kwargs = kwargs.clone();
+            let output = output.clone();
+
+            async move {
+                let mut batch = batch?;
+                let metadata = self.metadata(&batch, &query);
+
+                run_python_with_timeout(self.timeout, move || {
+                    transform_tiles(
+                        &module,
+                        &mut batch,
+                        batched,
+                        &kwargs,
+                        &metadata,
+                        output.as_ref(),
+                    )
+                })
+                .await
+            }
+        });
+
+        // the results of a batch are emitted in the order of its tiles
+        let tiles = results.flat_map(|results| {
+            let results = match results {
+                Ok(results) => results.into_iter().map(Ok).collect(),
+                Err(error) => vec![Err(error)],
+            };
+
+            futures::stream::iter(results)
+        });
+
+        Ok(tiles.boxed())
+    }
+
+    /// Query all vector inputs and add them to the keyword arguments as `vectors`, together with
+    /// the `state` of the query
+    async fn query_kwargs(
+        &self,
+        query: QueryRectangle,
+        ctx: &dyn QueryContext,
+    ) -> Result<(Arc<Py<PyDict>>, Arc<PyState>)> {
+        let mut vectors = Vec::with_capacity(self.vectors.len());
+        for vector in &self.vectors {
+            vectors.push(query_feature_collections(vector, query, ctx).await?);
+        }
+
+        let module = self.module.clone();
+        let base_kwargs = self.kwargs.clone();
+        let shared_state = self.shared_state.clone();
+        let model = self.model.clone();
+
+        run_python(move || {
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+
+            let state = match shared_state {
+                Some(state) => state,
+                None => Arc::new(PyState::new(
+                    py,
+                    &module,
+                    base_kwargs.as_ref(py),
+                    model.as_deref(),
+                )?),
+            };
+
+            let kwargs = base_kwargs.as_ref(py).copy().py_context(py)?;
+            kwargs.set_item("state", state.object(py)).py_context(py)?;
+
+            if !vectors.is_empty() {
+                let vectors = vectors
+                    .iter()
+                    .map(|collections| feature_collections_to_py(py, collections))
+                    .collect::<PyResult<Vec<_>>>()
+                    .py_context(py)?;
+                kwargs.set_item("vectors", vectors).py_context(py)?;
+            }
+
+            Ok((Arc::new(kwargs.into()), state))
+        })
+        .await
+    }
+
+    /// The metadata of the tiles in `batch`, a single object or a list if the calls are batched
+    fn metadata(
+        &self,
+        batch: &[Vec<RasterTile2D<TIn>>],
+        query: &QueryRectangle,
+    ) -> serde_json::Value {
+        let mut metadata = batch
+            .iter()
+            .map(|tiles| TileMetadata::new(tiles, self.spatial_reference, query));
+
+        let metadata = if self.batch_size.is_some() {
+            serde_json::to_value(metadata.collect::<Vec<_>>())
+        } else {
+            serde_json::to_value(metadata.next())
+        };
+
+        metadata.expect("metadata is serializable")
+    }
+
+    /// Query all input rasters and group their combined tiles into batches
+    ///
+    /// Without a batch size, every batch consists of a single tile.
+    fn query_batches<'a>(
+        &'a self,
+        query: QueryRectangle,
+        ctx: &'a dyn QueryContext,
+    ) -> Result<BoxStream<'a, Result<Vec<Vec<RasterTile2D<TIn>>>>>> {
+        let batch_size = self.batch_size.map_or(1, NonZeroUsize::get);
+
+        let batches = self
+            .query_zipped(query, ctx)?
+            .chunks(batch_size)
+            .map(|batch| batch.into_iter().collect::<Result<Vec<_>>>());
+
+        Ok(batches.boxed())
+    }
+
+    /// Query all input rasters and combine their tiles
+    fn query_zipped<'a>(
+        &'a self,
+        query: QueryRectangle,
+        ctx: &'a dyn QueryContext,
+    ) -> Result<BoxStream<'a, Result<Vec<RasterTile2D<TIn>>>>> {
+        let streams = self
+            .rasters
+            .iter()
+            .map(|raster| raster.query(query, ctx))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(zip_tile_streams(streams))
+    }
+}
+
+/// The keyword arguments of a single call, i.e., the runner's ones and the tiles' `metadata`
+fn call_kwargs<'py>(
+    py: Python<'py>,
+    kwargs: &Py<PyDict>,
+    metadata: &serde_json::Value,
+) -> PyResult<&'py PyDict> {
+    let call_kwargs = kwargs.as_ref(py).copy()?;
+    call_kwargs.set_item("metadata", json_to_py(py, metadata)?)?;
+
+    Ok(call_kwargs)
+}
+
+/// The hooks' input: the tiles of a single call, or a list of them if the calls are batched
+fn batch_to_py<'py, TIn>(
+    py: Python<'py>,
+    batch: &mut [Vec<RasterTile2D<TIn>>],
+    batched: bool,
+) -> PyResult<&'py PyAny>
+where
+    TIn: Pixel + numpy::Element,
+{
+    if !batched {
+        return tiles_into_py(py, &mut batch[0]);
+    }
+
+    let tiles = batch
+        .iter_mut()
+        .map(|tiles| tiles_into_py(py, tiles))
+        .collect::<PyResult<Vec<_>>>()?;
+
+    Ok(PyList::new(py, tiles))
+}
+
+fn fit_tiles<TIn>(
+    module: &PyScriptModule,
+    fit: &str,
+    batch: &mut [Vec<RasterTile2D<TIn>>],
+    batched: bool,
+    kwargs: &Py<PyDict>,
+    metadata: &serde_json::Value,
+) -> Result<()>
+where
+    TIn: Pixel + numpy::Element,
+{
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+    let pythonized_data = batch_to_py(py, batch, batched).py_context(py)?;
+    let kwargs = call_kwargs(py, kwargs, metadata).py_context(py)?;
+
+    module
+        .call(py, fit, (pythonized_data,), Some(kwargs))
+        .py_context(py)?;
+
+    Ok(())
+}
+
+fn transform_tiles<TIn, O>(
+    module: &PyScriptModule,
+    batch: &mut [Vec<RasterTile2D<TIn>>],
+    batched: bool,
+    kwargs: &Py<PyDict>,
+    metadata: &serde_json::Value,
+    output: &O,
+) -> Result<Vec<O::Item>>
+where
+    TIn: Pixel + numpy::Element,
+    O: PyOutput<TIn>,
+{
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+
+    let (result, buffer) = {
+        // the output buffer is only free of references after the pool of the call is released,
+        // and the result is kept as an owned object
+        // SAFETY: no reference of the pool is used after it is dropped
+        let pool = unsafe { py.new_pool() };
+        let py = pool.python();
+
+        let pythonized_data = batch_to_py(py, batch, batched).py_context(py)?;
+        let kwargs = call_kwargs(py, kwargs, metadata).py_context(py)?;
+
+        let buffer = if batched {
+            None
+        } else {
+            output.buffer(py, &batch[0]).py_context(py)?
+        };
+
+        let buffer = match buffer {
+            Some((buffer, out)) => {
+                kwargs.set_item("out", out).py_context(py)?;
+                Some(buffer)
+            }
+            None => None,
+        };
+
+        let result = module
+            .call(py, &module.hooks.tile, (pythonized_data,), Some(kwargs))
+            .py_context(py)?;
+
+        (result, buffer)
+    };
+
+    if !batched {
+        return Ok(vec![output.output(py, result, buffer, &batch[0])?]);
+    }
+
+    let results = result
+        .as_ref(py)
+        .iter()
+        .and_then(|results| results.collect::<PyResult<Vec<_>>>())
+        .py_context(py)?;
+
+    if results.len() != batch.len() {
+        return Err(Error::InvalidPythonOutput {
+            expected: format!("a sequence of {} tiles", batch.len()),
+            found: format!("{} tiles", results.len()),
+        }
+        .into());
+    }
+
+    results
+        .into_iter()
+        .zip(batch.iter())
+        .map(|(result, tiles)| output.output(py, result.into(), None, tiles))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::{PyHooks, PyScript};
+    use crate::test_util::{query_rectangle, tile_u8, CountingProcessor};
+    use geoengine_datatypes::spatial_reference::SpatialReference;
+    use geoengine_operators::engine::MockQueryContext;
+    use std::sync::atomic::Ordering;
+
+    /// The sums of the transformed tiles and how often the source was queried
+    async fn fit_and_transform(fit_buffer_bytes: Option<usize>) -> (Vec<i64>, usize) {
+        let processor = CountingProcessor::new(vec![
+            tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4]),
+            tile_u8([0, 1], [2, 2], vec![5, 6, 7, 8]),
+        ]);
+        let queries = processor.queries.clone();
+
+        let module = PyScript::Source(
+            "def fit(data, **kwargs):\n    pass\n\ndef tile(data, **kwargs):\n    return int(data.sum())\n"
+                .to_string(),
+        )
+        .load("fit_buffer")
+        .unwrap()
+        .compile(PyHooks {
+            tile: "tile".to_string(),
+            fit: Some("fit".to_string()),
+            setup: None,
+            teardown: None,
+        })
+        .unwrap();
+
+        let runner = PyRunner::new(
+            vec![processor.boxed()],
+            vec![],
+            module,
+            &serde_json::Value::Null,
+            SpatialReference::epsg_4326().into(),
+        )
+        .unwrap()
+        .with_fit_buffer(fit_buffer_bytes);
+
+        let sums = runner
+            .query(
+                query_rectangle(),
+                &MockQueryContext::new(0),
+                |result: &PyAny, _tiles: &[RasterTile2D<u8>]| Ok(result.extract::<i64>().unwrap()),
+            )
+            .map(|sum| sum.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        (sums, queries.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn buffered_fit_tiles_are_not_queried_again() {
+        // the two tiles have 8 bytes of pixels
+        assert_eq!(fit_and_transform(Some(8)).await, (vec![10, 26], 1));
+        assert_eq!(fit_and_transform(Some(7)).await, (vec![10, 26], 2));
+        assert_eq!(fit_and_transform(None).await, (vec![10, 26], 2));
+    }
+}
@@ -0,0 +1,225 @@
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The keyword parameters that a script declares, by name
+///
+/// ```python
+/// PARAMETERS = {
+///     'n_components': {'type': 'int', 'min': 1, 'default': 5},
+///     'whiten': {'type': 'bool', 'default': False},
+/// }
+/// ```
+pub type PyParameterSchema = BTreeMap<String, PyParameter>;
+
+/// A keyword parameter of a script
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PyParameter {
+    #[serde(rename = "type")]
+    pub data_type: PyParameterType,
+    /// The value that is used if a workflow omits the parameter, which is required otherwise
+    #[serde(default)]
+    pub default: Option<serde_json::Value>,
+    /// The smallest allowed value of a number
+    #[serde(default)]
+    pub min: Option<f64>,
+    /// The largest allowed value of a number
+    #[serde(default)]
+    pub max: Option<f64>,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+/// The Python type of a parameter, as it is called in the declaration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PyParameterType {
+    Int,
+    Float,
+    Str,
+    Bool,
+    List,
+    Dict,
+}
+
+impl PyParameterType {
+    fn matches(self, value: &serde_json::Value) -> bool {
+        match self {
+            PyParameterType::Int => value.is_i64() || value.is_u64(),
+            PyParameterType::Float => value.is_number(),
+            PyParameterType::Str => value.is_string(),
+            PyParameterType::Bool => value.is_boolean(),
+            PyParameterType::List => value.is_array(),
+            PyParameterType::Dict => value.is_object(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PyParameterType::Int => "int",
+            PyParameterType::Float => "float",
+            PyParameterType::Str => "str",
+            PyParameterType::Bool => "bool",
+            PyParameterType::List => "list",
+            PyParameterType::Dict => "dict",
+        }
+    }
+}
+
+/// A parameter of a workflow that does not match the schema of its script
+#[derive(Debug, Clone, PartialEq)]
+pub struct PyParameterViolation {
+    pub field: String,
+    pub reason: String,
+}
+
+impl fmt::Display for PyParameterViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\" {}", self.field, self.reason)
+    }
+}
+
+/// Check `parameters` against `schema` and add the defaults of the omitted ones
+///
+/// All offending fields are reported at once, including the ones that the schema does not know.
+pub fn validate_parameters(
+    schema: &PyParameterSchema,
+    parameters: &serde_json::Value,
+) -> Result<serde_json::Value> {
+    let mut parameters = match parameters {
+        serde_json::Value::Object(parameters) => parameters.clone(),
+        _ => serde_json::Map::new(),
+    };
+
+    let mut violations = parameters
+        .keys()
+        .filter(|field| !schema.contains_key(field.as_str()))
+        .map(|field| PyParameterViolation {
+            field: field.clone(),
+            reason: "is not a parameter of the script".to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    for (field, parameter) in schema {
+        if !parameters.contains_key(field) {
+            match &parameter.default {
+                Some(default) => {
+                    parameters.insert(field.clone(), default.clone());
+                }
+                None => {
+                    violations.push(PyParameterViolation {
+                        field: field.clone(),
+                        reason: "is required".to_string(),
+                    });
+                    continue;
+                }
+            }
+        }
+
+        if let Some(reason) = check_value(parameter, &parameters[field]) {
+            violations.push(PyParameterViolation {
+                field: field.clone(),
+                reason,
+            });
+        }
+    }
+
+    if !violations.is_empty() {
+        return Err(Error::ParametersViolateSchema { violations });
+    }
+
+    Ok(serde_json::Value::Object(parameters))
+}
+
+fn check_value(parameter: &PyParameter, value: &serde_json::Value) -> Option<String> {
+    if !parameter.data_type.matches(value) {
+        return Some(format!(
+            "must be of type {}, found {}",
+            parameter.data_type.name(),
+            value
+        ));
+    }
+
+    let number = value.as_f64()?;
+
+    match (parameter.min, parameter.max) {
+        (Some(min), _) if number < min => {
+            Some(format!("must be at least {}, found {}", min, value))
+        }
+        (_, Some(max)) if number > max => Some(format!("must be at most {}, found {}", max, value)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> PyParameterSchema {
+        serde_json::from_value(json!({
+            "n_components": {"type": "int", "min": 1, "max": 100},
+            "ratio": {"type": "float", "default": 0.5},
+            "whiten": {"type": "bool", "default": false},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn deserializes_parameters() {
+        assert_eq!(
+            serde_json::from_value::<PyParameterSchema>(
+                json!({"n_components": {"type": "int", "default": 5}})
+            )
+            .unwrap()["n_components"],
+            PyParameter {
+                data_type: PyParameterType::Int,
+                default: Some(json!(5)),
+                min: None,
+                max: None,
+                description: None,
+            }
+        );
+    }
+
+    #[test]
+    fn adds_defaults() {
+        assert_eq!(
+            validate_parameters(&schema(), &json!({"n_components": 5, "ratio": 1})).unwrap(),
+            json!({"n_components": 5, "ratio": 1, "whiten": false})
+        );
+    }
+
+    #[test]
+    fn lists_all_violations() {
+        let result = validate_parameters(
+            &schema(),
+            &json!({"n_compnents": 5, "ratio": "half", "whiten": 1}),
+        );
+
+        match result {
+            Err(Error::ParametersViolateSchema { violations }) => {
+                let fields = violations
+                    .iter()
+                    .map(|violation| violation.field.as_str())
+                    .collect::<Vec<_>>();
+
+                assert_eq!(
+                    fields,
+                    vec!["n_compnents", "n_components", "ratio", "whiten"]
+                );
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn checks_ranges() {
+        assert!(validate_parameters(&schema(), &json!({"n_components": 0})).is_err());
+        assert!(validate_parameters(&schema(), &json!({"n_components": 101})).is_err());
+        assert!(validate_parameters(&schema(), &json!({"n_components": 2.5})).is_err());
+        assert!(validate_parameters(&schema(), &json!({"n_components": 100})).is_ok());
+    }
+}
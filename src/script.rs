@@ -0,0 +1,320 @@
+use crate::config;
+use crate::error::{self, Error, PyResultExt, Result};
+use crate::interpreter::prepare_interpreter;
+use crate::plugin::plugin_registry;
+use crate::reload::watch_scripts;
+use crate::schema::PyParameterSchema;
+use geoengine_services::util::config::get_config_element;
+use pyo3::types::{PyDict, PyModule, PyTuple};
+use pyo3::{IntoPy, Py, PyObject, PyResult, Python};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::path::{Component, Path, PathBuf};
+
+/// Evaluates the literal that a script assigns to a module level name
+const READ_DECLARATION: &str = r#"
+import ast
+import json
+
+
+def declaration(code, file_name, name):
+    for node in ast.parse(code, file_name).body:
+        targets = node.targets if isinstance(node, ast.Assign) else []
+        if any(isinstance(target, ast.Name) and target.id == name for target in targets):
+            return json.dumps(ast.literal_eval(node.value), allow_nan=False)
+    return None
+"#;
+
+/// The source of a Python script that is run by a Python operator
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PyScript {
+    /// A script file, relative to the configured `python.script_root`
+    Path(PathBuf),
+    /// The source code of the script itself
+    Source(String),
+    /// The script of the plugin that declares this name, see `crate::plugin`
+    Plugin(String),
+}
+
+/// A script whose source code was read and is ready to be loaded as a Python module
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadedScript {
+    pub code: String,
+    pub file_name: String,
+    pub module_name: String,
+}
+
+/// The names of the Python functions that a script provides to an operator
+///
+/// A query runs in two phases. If there is a `fit` function, the training phase passes every tile
+/// of the query to it and yields nothing. Afterwards, the transform phase passes every tile to the
+/// `tile` function, whose results are the output of the query. Thus, `fit` has seen all tiles
+/// before `tile` is called for the first time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PyHooks {
+    /// Called for every tile, returns the new tile data, which an in-process raster operator lets
+    /// it write into its `out` argument
+    pub tile: String,
+    /// Called for every tile in the training phase, before any tile is transformed
+    pub fit: Option<String>,
+    /// Called once before the first tile is processed
+    pub setup: Option<String>,
+    /// Called once after the processing has finished
+    pub teardown: Option<String>,
+}
+
+impl PyHooks {
+    /// All configured function names, starting with the tile function
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.tile.as_str()).chain(
+            [&self.fit, &self.setup, &self.teardown]
+                .iter()
+                .filter_map(|name| name.as_deref()),
+        )
+    }
+
+    /// All configured function names with the kind of `crate::sdk` hook they may be decorated as
+    fn kinds(&self) -> impl Iterator<Item = (&str, Option<&'static str>)> {
+        vec![
+            (Some(&self.tile), Some("tile")),
+            (self.fit.as_ref(), Some("fit")),
+            (self.setup.as_ref(), None),
+            (self.teardown.as_ref(), None),
+        ]
+        .into_iter()
+        .filter_map(|(name, kind)| name.map(|name| (name.as_str(), kind)))
+    }
+}
+
+/// A Python module whose hooks were checked to exist and to be callable
+pub struct PyScriptModule {
+    module: Py<PyModule>,
+    pub hooks: PyHooks,
+}
+
+impl PyScript {
+    /// Read the script source, resolving script paths against the configured script root
+    ///
+    /// With `python.hot_reload`, the scripts are watched from now on, see `crate::reload`.
+    pub fn load(&self, module_name: &str) -> Result<LoadedScript> {
+        watch_scripts()?;
+
+        match self {
+            PyScript::Path(path) => {
+                let script_root = get_config_element::<config::Python>()?.script_root;
+                let path = resolve_script_path(&script_root, path)?;
+
+                let code = std::fs::read_to_string(&path)
+                    .context(error::CannotReadScript { path: path.clone() })?;
+
+                Ok(LoadedScript {
+                    code,
+                    file_name: path.to_string_lossy().into_owned(),
+                    module_name: module_name.to_string(),
+                })
+            }
+            PyScript::Source(code) => Ok(LoadedScript {
+                code: code.clone(),
+                file_name: format!("{}.py", module_name),
+                module_name: module_name.to_string(),
+            }),
+            PyScript::Plugin(name) => {
+                let registry = plugin_registry()?;
+                let path = &registry.get(name)?.path;
+
+                let code = std::fs::read_to_string(path)
+                    .context(error::CannotReadScript { path: path.clone() })?;
+
+                Ok(LoadedScript {
+                    code,
+                    file_name: path.to_string_lossy().into_owned(),
+                    module_name: module_name.to_string(),
+                })
+            }
+        }
+    }
+}
+
+impl LoadedScript {
+    /// The parameters that the script declares in its module level `PARAMETERS` dict or, for a
+    /// plugin, in the `parameters` of its `OPERATOR` dict
+    pub fn parameter_schema(&self) -> Result<Option<PyParameterSchema>> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let schema = match read_declaration(py, &self.code, &self.file_name, "PARAMETERS")? {
+            Some(schema) => Some(schema),
+            None => read_declaration(py, &self.code, &self.file_name, "OPERATOR")?
+                .and_then(|operator| operator.get("parameters").cloned()),
+        };
+
+        schema
+            .map(|schema| {
+                serde_json::from_value(schema).map_err(|error| Error::InvalidParameterSchema {
+                    reason: error.to_string(),
+                })
+            })
+            .transpose()
+    }
+
+    /// Load the script as a Python module and check that all `hooks` are callable functions
+    ///
+    /// The interpreter is prepared according to the `[python]` settings first, and scripts can
+    /// import the `geoengine` module, see `crate::sdk`.
+    pub fn compile(&self, hooks: PyHooks) -> Result<PyScriptModule> {
+        prepare_interpreter()?;
+
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        crate::sdk::register(py).py_context(py)?;
+
+        // every script gets a fresh module that is not registered in `sys.modules`, like in the
+        // worker processes, so scripts with the same module name do not share their globals and
+        // cannot replace an installed module
+        let module = PyModule::new(py, &self.module_name).py_context(py)?;
+        module
+            .add("__file__", self.file_name.as_str())
+            .py_context(py)?;
+
+        let builtins = py.import("builtins").py_context(py)?;
+        builtins
+            .call1(
+                "compile",
+                (self.code.as_str(), self.file_name.as_str(), "exec"),
+            )
+            .and_then(|code| builtins.call1("exec", (code, module.dict())))
+            .py_context(py)?;
+
+        for name in hooks.names() {
+            let is_callable = module
+                .getattr(name)
+                .map(|function| function.is_callable())
+                .unwrap_or(false);
+
+            if !is_callable {
+                return Err(Error::MissingPythonFunction {
+                    module: self.module_name.clone(),
+                    function: name.to_string(),
+                });
+            }
+        }
+
+        // a function that `crate::sdk` decorated for one hook cannot be called as another
+        for (name, expected) in hooks.kinds() {
+            let function = module.getattr(name).py_context(py)?;
+
+            match crate::sdk::hook_kind(function) {
+                Some(kind) if Some(kind.as_str()) != expected => {
+                    return Err(Error::MisplacedPythonHook {
+                        module: self.module_name.clone(),
+                        function: name.to_string(),
+                        kind,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        Ok(PyScriptModule {
+            module: module.into_py(py),
+            hooks,
+        })
+    }
+}
+
+/// The value that `code` assigns to the module level `name`, e.g., `OPERATOR`, as JSON
+///
+/// Only the syntax tree is inspected, so the script does not run and the value must be a literal.
+pub fn read_declaration(
+    py: Python,
+    code: &str,
+    file_name: &str,
+    name: &str,
+) -> Result<Option<serde_json::Value>> {
+    let json = PyModule::from_code(
+        py,
+        READ_DECLARATION,
+        "read_declaration.py",
+        "read_declaration",
+    )
+    .and_then(|reader| reader.call1("declaration", (code, file_name, name)))
+    .and_then(|json| json.extract::<Option<String>>())
+    .py_context(py)?;
+
+    Ok(json.map(|json| serde_json::from_str(&json).expect("json.dumps returns valid JSON")))
+}
+
+impl PyScriptModule {
+    /// Call the module's function `name` with positional `args` and keyword arguments `kwargs`
+    pub fn call(
+        &self,
+        py: Python,
+        name: &str,
+        args: impl IntoPy<Py<PyTuple>>,
+        kwargs: Option<&PyDict>,
+    ) -> PyResult<PyObject> {
+        self.module
+            .as_ref(py)
+            .call(name, args, kwargs)
+            .map(Into::into)
+    }
+
+    pub fn clone_ref(&self, py: Python) -> Self {
+        Self {
+            module: self.module.clone_ref(py),
+            hooks: self.hooks.clone(),
+        }
+    }
+}
+
+/// Join `path` to `script_root` while making sure it does not leave the root
+fn resolve_script_path(script_root: &Path, path: &Path) -> Result<PathBuf> {
+    let stays_in_root = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+    if !stays_in_root {
+        return Err(Error::ScriptPathOutsideRoot {
+            path: path.to_path_buf(),
+        });
+    }
+
+    Ok(script_root.join(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_relative_paths() {
+        assert_eq!(
+            resolve_script_path(Path::new("scripts"), Path::new("sub/ipca.py")).unwrap(),
+            PathBuf::from("scripts/sub/ipca.py")
+        );
+    }
+
+    #[test]
+    fn rejects_paths_outside_root() {
+        assert!(resolve_script_path(Path::new("scripts"), Path::new("../secret.py")).is_err());
+        assert!(resolve_script_path(Path::new("scripts"), Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn deserializes_script_sources() {
+        assert_eq!(
+            serde_json::from_str::<PyScript>(r#"{"path": "ipca.py"}"#).unwrap(),
+            PyScript::Path("ipca.py".into())
+        );
+        assert_eq!(
+            serde_json::from_str::<PyScript>(r#"{"source": "x = 1"}"#).unwrap(),
+            PyScript::Source("x = 1".to_string())
+        );
+        assert_eq!(
+            serde_json::from_str::<PyScript>(r#"{"plugin": "ipca"}"#).unwrap(),
+            PyScript::Plugin("ipca".to_string())
+        );
+    }
+}
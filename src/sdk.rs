@@ -0,0 +1,410 @@
+use crate::metadata::{QueryMetadata, TileMetadata};
+use geoengine_datatypes::primitives::{self, BoundingBox2D, SpatialResolution};
+use geoengine_datatypes::raster;
+use geoengine_operators::engine;
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use pyo3::{wrap_pyfunction, wrap_pymodule};
+
+/// The `geoengine` module that scripts can import in the embedded interpreter
+///
+/// Functions that are decorated with `@raster_operator` or `@fit` get `Tile` objects, or a list
+/// of them if the calls are batched, instead of the bare arrays and the `metadata` dict. The
+/// module is native, so scripts that use it cannot run on the subprocess backend.
+#[pymodule]
+fn geoengine(_py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_class::<Tile>()?;
+    module.add_class::<TimeInterval>()?;
+    module.add_class::<GeoTransform>()?;
+    module.add_class::<QueryRectangle>()?;
+    module.add_class::<NoData>()?;
+    module.add_class::<Hook>()?;
+
+    module.add_function(wrap_pyfunction!(raster_operator, module)?)?;
+    module.add_function(wrap_pyfunction!(fit, module)?)?;
+
+    Ok(())
+}
+
+/// Make the `geoengine` module importable if it is not yet
+pub fn register(py: Python) -> PyResult<()> {
+    let modules = py.import("sys")?.getattr("modules")?.downcast::<PyDict>()?;
+
+    if !modules.contains("geoengine")? {
+        modules.set_item("geoengine", wrap_pymodule!(geoengine)(py))?;
+    }
+
+    Ok(())
+}
+
+/// The data of a tile as a masked array of shape `(bands, rows, columns)` and its metadata
+#[pyclass]
+pub struct Tile {
+    #[pyo3(get)]
+    data: PyObject,
+    #[pyo3(get)]
+    time: TimeInterval,
+    #[pyo3(get)]
+    tile_position: (isize, isize),
+    #[pyo3(get)]
+    geo_transform: GeoTransform,
+    #[pyo3(get)]
+    spatial_reference: String,
+    #[pyo3(get)]
+    no_data: Vec<NoData>,
+    #[pyo3(get)]
+    query: QueryRectangle,
+}
+
+impl Tile {
+    fn new(data: PyObject, metadata: TileMetadata) -> PyResult<Self> {
+        let [row, column] = metadata.tile_position;
+
+        Ok(Self {
+            data,
+            time: TimeInterval::new(metadata.time[0], metadata.time[1])?,
+            tile_position: (row, column),
+            geo_transform: GeoTransform::from_affine(metadata.geo_transform),
+            spatial_reference: metadata.spatial_reference,
+            no_data: metadata
+                .no_data
+                .into_iter()
+                .map(|value| NoData { value })
+                .collect(),
+            query: QueryRectangle::from_metadata(&metadata.query)?,
+        })
+    }
+}
+
+#[pymethods]
+impl Tile {
+    /// The data of band `index` as a 2D masked array
+    fn band(&self, py: Python, index: usize) -> PyResult<PyObject> {
+        self.data.as_ref(py).get_item(index).map(Into::into)
+    }
+}
+
+#[pyclass]
+#[derive(Clone)]
+pub struct TimeInterval {
+    inner: primitives::TimeInterval,
+}
+
+#[pymethods]
+impl TimeInterval {
+    /// A time interval from `start` to `end` in milliseconds since the Unix epoch
+    #[new]
+    fn new(start: i64, end: i64) -> PyResult<Self> {
+        primitives::TimeInterval::new(start, end)
+            .map(|inner| Self { inner })
+            .map_err(|error| PyValueError::new_err(error.to_string()))
+    }
+
+    #[getter]
+    fn start(&self) -> i64 {
+        self.inner.start().inner()
+    }
+
+    #[getter]
+    fn end(&self) -> i64 {
+        self.inner.end().inner()
+    }
+
+    fn contains(&self, other: &TimeInterval) -> bool {
+        self.inner.contains(&other.inner)
+    }
+}
+
+#[pyclass]
+#[derive(Clone)]
+pub struct GeoTransform {
+    inner: raster::GeoTransform,
+}
+
+impl GeoTransform {
+    fn from_affine([x_pixel_size, _, origin_x, _, y_pixel_size, origin_y]: [f64; 6]) -> Self {
+        Self {
+            inner: raster::GeoTransform::new(
+                (origin_x, origin_y).into(),
+                x_pixel_size,
+                y_pixel_size,
+            ),
+        }
+    }
+}
+
+#[pymethods]
+impl GeoTransform {
+    #[new]
+    fn new(origin: (f64, f64), x_pixel_size: f64, y_pixel_size: f64) -> Self {
+        Self {
+            inner: raster::GeoTransform::new(origin.into(), x_pixel_size, y_pixel_size),
+        }
+    }
+
+    #[getter]
+    fn origin(&self) -> (f64, f64) {
+        let origin = self.inner.origin_coordinate;
+        (origin.x, origin.y)
+    }
+
+    #[getter]
+    fn x_pixel_size(&self) -> f64 {
+        self.inner.x_pixel_size
+    }
+
+    #[getter]
+    fn y_pixel_size(&self) -> f64 {
+        self.inner.y_pixel_size
+    }
+
+    /// The coefficients in the order of `affine.Affine(a, b, c, d, e, f)`, as used by rasterio
+    #[getter]
+    fn affine(&self) -> (f64, f64, f64, f64, f64, f64) {
+        let (origin_x, origin_y) = self.origin();
+        (
+            self.inner.x_pixel_size,
+            0.,
+            origin_x,
+            0.,
+            self.inner.y_pixel_size,
+            origin_y,
+        )
+    }
+
+    /// The coordinate of the upper left corner of the pixel at `row` and `column`
+    fn coordinate(&self, row: f64, column: f64) -> (f64, f64) {
+        let (origin_x, origin_y) = self.origin();
+        (
+            origin_x + column * self.inner.x_pixel_size,
+            origin_y + row * self.inner.y_pixel_size,
+        )
+    }
+}
+
+#[pyclass]
+#[derive(Clone)]
+pub struct QueryRectangle {
+    inner: engine::QueryRectangle,
+}
+
+impl QueryRectangle {
+    fn from_metadata(query: &QueryMetadata) -> PyResult<Self> {
+        let [min_x, min_y, max_x, max_y] = query.bbox;
+        let [x_resolution, y_resolution] = query.spatial_resolution;
+
+        Self::new(
+            (min_x, min_y, max_x, max_y),
+            TimeInterval::new(query.time[0], query.time[1])?,
+            (x_resolution, y_resolution),
+        )
+    }
+}
+
+#[pymethods]
+impl QueryRectangle {
+    #[new]
+    fn new(
+        bbox: (f64, f64, f64, f64),
+        time_interval: TimeInterval,
+        spatial_resolution: (f64, f64),
+    ) -> PyResult<Self> {
+        let (min_x, min_y, max_x, max_y) = bbox;
+        let value_error =
+            |error: geoengine_datatypes::error::Error| PyValueError::new_err(error.to_string());
+
+        Ok(Self {
+            inner: engine::QueryRectangle {
+                bbox: BoundingBox2D::new((min_x, min_y).into(), (max_x, max_y).into())
+                    .map_err(value_error)?,
+                time_interval: time_interval.inner,
+                spatial_resolution: SpatialResolution::new(
+                    spatial_resolution.0,
+                    spatial_resolution.1,
+                )
+                .map_err(value_error)?,
+            },
+        })
+    }
+
+    /// The queried area as `(min_x, min_y, max_x, max_y)`
+    #[getter]
+    fn bbox(&self) -> (f64, f64, f64, f64) {
+        let (lower_left, upper_right) =
+            (self.inner.bbox.lower_left(), self.inner.bbox.upper_right());
+        (lower_left.x, lower_left.y, upper_right.x, upper_right.y)
+    }
+
+    #[getter]
+    fn time_interval(&self) -> TimeInterval {
+        TimeInterval {
+            inner: self.inner.time_interval,
+        }
+    }
+
+    #[getter]
+    fn spatial_resolution(&self) -> (f64, f64) {
+        (
+            self.inner.spatial_resolution.x,
+            self.inner.spatial_resolution.y,
+        )
+    }
+}
+
+/// The no-data value of a band, if it has one
+#[pyclass]
+#[derive(Clone)]
+pub struct NoData {
+    #[pyo3(get)]
+    value: Option<f64>,
+}
+
+#[pymethods]
+impl NoData {
+    #[new]
+    fn new(value: Option<f64>) -> Self {
+        Self { value }
+    }
+
+    /// Whether `value` is the no-data value, where a NaN no-data value matches all NaNs
+    fn is_no_data(&self, value: f64) -> bool {
+        self.value.map_or(false, |no_data| {
+            value == no_data || (value.is_nan() && no_data.is_nan())
+        })
+    }
+}
+
+/// A decorated function that gets `Tile` objects instead of arrays and metadata
+#[pyclass]
+pub struct Hook {
+    function: PyObject,
+    /// Which hook the function is meant for, i.e., `tile` or `fit`
+    #[pyo3(get)]
+    kind: String,
+}
+
+#[pymethods]
+impl Hook {
+    #[call]
+    #[args(kwargs = "**")]
+    fn __call__(&self, py: Python, data: &PyAny, kwargs: Option<&PyDict>) -> PyResult<PyObject> {
+        let kwargs = match kwargs {
+            Some(kwargs) => kwargs.copy()?,
+            None => PyDict::new(py),
+        };
+
+        let metadata = kwargs
+            .get_item("metadata")
+            .ok_or_else(|| PyTypeError::new_err("the hook must be called with metadata"))?;
+        let tiles = tiles_from_py(py, data, metadata)?;
+        kwargs.del_item("metadata")?;
+
+        self.function.call(py, (tiles,), Some(kwargs))
+    }
+}
+
+/// The kind of hook that `function` was decorated as, if it is a `Hook`
+pub fn hook_kind(function: &PyAny) -> Option<String> {
+    function
+        .downcast::<PyCell<Hook>>()
+        .ok()
+        .map(|hook| hook.borrow().kind.clone())
+}
+
+/// Mark `function` as a tile hook that gets `Tile` objects
+#[pyfunction]
+fn raster_operator(function: PyObject) -> Hook {
+    Hook {
+        function,
+        kind: "tile".to_string(),
+    }
+}
+
+/// Mark `function` as a fit hook that gets `Tile` objects
+#[pyfunction]
+fn fit(function: PyObject) -> Hook {
+    Hook {
+        function,
+        kind: "fit".to_string(),
+    }
+}
+
+/// A `Tile` for the arguments of an undecorated hook, or a list of them for a batch
+fn tiles_from_py(py: Python, data: &PyAny, metadata: &PyAny) -> PyResult<PyObject> {
+    if let (Ok(data), Ok(metadata)) = (data.downcast::<PyList>(), metadata.downcast::<PyList>()) {
+        let tiles = data
+            .iter()
+            .zip(metadata.iter())
+            .map(|(data, metadata)| tile_from_py(py, data, metadata))
+            .collect::<PyResult<Vec<_>>>()?;
+
+        return Ok(tiles.into_py(py));
+    }
+
+    Ok(tile_from_py(py, data, metadata)?.into_py(py))
+}
+
+fn tile_from_py(py: Python, data: &PyAny, metadata: &PyAny) -> PyResult<Tile> {
+    // the metadata dict is built from a `TileMetadata`, so it takes the same way back
+    let json = py
+        .import("json")?
+        .call1("dumps", (metadata,))?
+        .extract::<String>()?;
+    let metadata = serde_json::from_str::<TileMetadata>(&json)
+        .map_err(|error| PyValueError::new_err(error.to_string()))?;
+
+    Tile::new(data.into(), metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::IntoPyDict;
+
+    #[test]
+    fn provides_the_geoengine_module() {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        register(py).unwrap();
+        // registering again keeps the module
+        register(py).unwrap();
+
+        let geoengine = py.import("geoengine").unwrap();
+        let locals = [("geoengine", geoengine)].into_py_dict(py);
+
+        let affine = py
+            .eval(
+                "geoengine.GeoTransform((10., 50.), 0.5, -0.5).affine",
+                None,
+                Some(locals),
+            )
+            .unwrap()
+            .extract::<(f64, f64, f64, f64, f64, f64)>()
+            .unwrap();
+        assert_eq!(affine, (0.5, 0., 10., 0., -0.5, 50.));
+
+        assert!(py
+            .eval("geoengine.TimeInterval(5, 1)", None, Some(locals))
+            .is_err());
+        assert!(py
+            .eval(
+                "geoengine.TimeInterval(0, 10).contains(geoengine.TimeInterval(2, 3))",
+                None,
+                Some(locals)
+            )
+            .unwrap()
+            .is_true()
+            .unwrap());
+        assert!(py
+            .eval(
+                "geoengine.NoData(float('nan')).is_no_data(float('nan'))",
+                None,
+                Some(locals)
+            )
+            .unwrap()
+            .is_true()
+            .unwrap());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::convert::json_to_kwargs;
+use crate::error::{PyResultExt, Result};
+use crate::model::PyModel;
+use crate::script::PyScriptModule;
+use crate::worker::spawn_python;
+use pyo3::types::PyDict;
+use pyo3::{PyObject, Python};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// How long the state object of a script lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PyStateScope {
+    /// Every query gets a fresh state object
+    Query,
+    /// All queries of an initialized operator share one state object
+    Operator,
+}
+
+impl Default for PyStateScope {
+    fn default() -> Self {
+        PyStateScope::Query
+    }
+}
+
+/// The state object that is passed to all hooks of a script as the keyword argument `state`
+///
+/// The setup hook is called when the state is created and the teardown hook when it is dropped.
+pub struct PyState {
+    module: Option<PyScriptModule>,
+    /// Only taken by `drop`, which hands both to the teardown hook
+    state: Option<PyObject>,
+}
+
+impl PyState {
+    /// Use the state of a loaded `model`, or create an empty namespace as state and call the
+    /// setup hook with it and `kwargs`
+    pub fn new(
+        py: Python,
+        module: &PyScriptModule,
+        kwargs: &PyDict,
+        model: Option<&PyModel>,
+    ) -> Result<Self> {
+        if let Some(state) = model.and_then(|model| model.load_state(py)) {
+            return Ok(Self {
+                module: Some(module.clone_ref(py)),
+                state: Some(state?),
+            });
+        }
+
+        let state: PyObject = py
+            .import("types")
+            .and_then(|types| types.call0("SimpleNamespace"))
+            .py_context(py)?
+            .into();
+
+        if let Some(setup) = &module.hooks.setup {
+            let kwargs = kwargs.copy().py_context(py)?;
+            kwargs.set_item("state", &state).py_context(py)?;
+
+            module.call(py, setup, (), Some(kwargs)).py_context(py)?;
+        }
+
+        Ok(Self {
+            module: Some(module.clone_ref(py)),
+            state: Some(state),
+        })
+    }
+
+    /// Create the state that all queries of an operator share if the scope is `Operator`
+    pub fn shared(
+        scope: PyStateScope,
+        module: &PyScriptModule,
+        parameters: &serde_json::Value,
+        model: Option<&PyModel>,
+    ) -> Result<Option<Arc<Self>>> {
+        match scope {
+            PyStateScope::Query => Ok(None),
+            PyStateScope::Operator => Python::with_gil(|py| {
+                let kwargs = json_to_kwargs(py, parameters).py_context(py)?;
+
+                Ok(Some(Arc::new(Self::new(py, module, kwargs, model)?)))
+            }),
+        }
+    }
+
+    pub fn object(&self, py: Python) -> PyObject {
+        self.state
+            .as_ref()
+            .expect("the state is only taken when it is dropped")
+            .clone_ref(py)
+    }
+}
+
+impl Drop for PyState {
+    fn drop(&mut self) {
+        let (module, state, teardown) = match (self.module.take(), self.state.take()) {
+            (Some(module), Some(state)) => match module.hooks.teardown.clone() {
+                Some(teardown) => (module, state, teardown),
+                None => return,
+            },
+            _ => return,
+        };
+
+        // the state may be dropped on a thread of the async runtime, which must not wait for the
+        // GIL, so the teardown hook runs on the Python worker pool
+        spawn_python(move || {
+            Python::with_gil(|py| {
+                let kwargs = PyDict::new(py);
+
+                // there is no caller to report to, so print the exception like Python does
+                let result = kwargs
+                    .set_item("state", &state)
+                    .and_then(|_| module.call(py, &teardown, (), Some(kwargs)));
+                if let Err(error) = result {
+                    error.print(py);
+                }
+            });
+        });
+    }
+}
@@ -0,0 +1,1135 @@
+use crate::config;
+use crate::convert::missing_no_data_value;
+use crate::error::{self, Error};
+use crate::metadata::TileMetadata;
+use crate::model::PyModel;
+use crate::reload::script_generation;
+use crate::script::{LoadedScript, PyHooks};
+use crate::util::{output_no_data_value, output_tile, pixel_to_f64, zip_tile_streams, FitBuffer};
+use crate::worker::spawn_python;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use geoengine_datatypes::raster::{Pixel, RasterDataType, RasterTile2D};
+use geoengine_datatypes::spatial_reference::SpatialReferenceOption;
+use geoengine_operators::engine::{QueryContext, QueryRectangle, RasterQueryProcessor};
+use geoengine_operators::util::Result;
+use geoengine_services::util::config::get_config_element;
+use lazy_static::lazy_static;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// The Python side of the worker processes
+const WORKER_SCRIPT: &str = include_str!("subprocess_worker.py");
+
+/// Where the Python code of an operator runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PyBackend {
+    /// In the interpreter that is embedded into the server
+    InProcess,
+    /// In separate Python processes, so a crashing script only fails its query
+    Subprocess,
+}
+
+impl Default for PyBackend {
+    fn default() -> Self {
+        PyBackend::InProcess
+    }
+}
+
+impl PyBackend {
+    /// The backend an operator chose or, otherwise, the configured `python.backend`
+    pub fn configured(backend: Option<Self>) -> error::Result<Self> {
+        match backend {
+            Some(backend) => Ok(backend),
+            None => Ok(get_config_element::<config::Python>()?.backend),
+        }
+    }
+}
+
+/// Limits on the resources that the worker process of a query may use
+///
+/// The limits are enforced by the operating system and the worker's timer signals, so they
+/// require the subprocess backend on a Unix system.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PyResourceLimits {
+    /// The data memory of the worker process in MiB, i.e., its `RLIMIT_DATA`
+    pub memory_mb: Option<u64>,
+    /// The CPU time in milliseconds that the script may use per query
+    pub cpu_time_ms: Option<u64>,
+}
+
+impl PyResourceLimits {
+    pub fn is_unlimited(&self) -> bool {
+        self.memory_mb.is_none() && self.cpu_time_ms.is_none()
+    }
+}
+
+/// The requests of the control protocol, see `subprocess_worker.py`
+#[derive(Debug, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request<'r> {
+    Prepare {
+        paths: Vec<&'r Path>,
+        virtualenv: Option<&'r Path>,
+        preload_modules: &'r [String],
+    },
+    Load {
+        code: &'r str,
+        file_name: &'r str,
+        module_name: &'r str,
+        hooks: Vec<&'r str>,
+    },
+    Start {
+        parameters: &'r serde_json::Value,
+        setup: Option<&'r str>,
+        model: Option<&'r Path>,
+        limits: &'r PyResourceLimits,
+    },
+    Save {
+        model: &'r Path,
+    },
+    Call {
+        hook: &'r str,
+        input: &'r Path,
+        shape: [usize; 3],
+        dtype: &'static str,
+        no_data: Vec<Option<f64>>,
+        metadata: &'r TileMetadata,
+        output: Option<&'r Path>,
+        output_no_data: Option<f64>,
+        timeout_ms: Option<u128>,
+    },
+    Stop {
+        teardown: Option<&'r str>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct Reply {
+    ok: bool,
+    missing: Option<String>,
+    missing_modules: Option<Vec<String>>,
+    missing_no_data: Option<String>,
+    python_version: Option<String>,
+    exceeded: Option<String>,
+    shape: Option<Vec<usize>>,
+    dtype: Option<String>,
+    exception_type: Option<String>,
+    message: Option<String>,
+    traceback: Option<String>,
+}
+
+/// A Python process that runs `subprocess_worker.py`
+///
+/// Requests and replies are framed JSON messages on the process' stdin and stdout. The tile data
+/// is exchanged through two files in shared memory instead, whose paths are part of the requests.
+struct PyWorkerProcess {
+    child: Child,
+    requests: ChildStdin,
+    replies: ChildStdout,
+    input: PathBuf,
+    output: PathBuf,
+    /// The `script_generation` at the start, the imported modules of older ones are outdated
+    generation: u64,
+    signals: PyWorkerSignals,
+}
+
+/// Interrupts the requests of a worker process from other threads
+///
+/// Signals are only sent while a request runs, i.e., before the process is reaped, so they never
+/// hit another process that got the same pid.
+#[derive(Debug, Clone)]
+struct PyWorkerSignals {
+    pid: libc::pid_t,
+    request: Arc<Mutex<WorkerRequest>>,
+}
+
+/// The request that a worker process handles, by a number that is unique among all workers
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WorkerRequest {
+    Idle,
+    Running(u64),
+    Exited,
+}
+
+impl PyWorkerSignals {
+    fn new(pid: u32) -> Self {
+        Self {
+            pid: pid as libc::pid_t,
+            request: Arc::new(Mutex::new(WorkerRequest::Idle)),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<WorkerRequest> {
+        // the request is a plain value, so it is still valid if a thread panicked holding it
+        self.request.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn start(&self) {
+        static NEXT_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+        let mut request = self.lock();
+        if *request != WorkerRequest::Exited {
+            *request = WorkerRequest::Running(NEXT_REQUEST.fetch_add(1, Ordering::Relaxed));
+        }
+    }
+
+    fn finish(&self) {
+        let mut request = self.lock();
+        if let WorkerRequest::Running(_) = *request {
+            *request = WorkerRequest::Idle;
+        }
+    }
+
+    /// Must be called before the process is reaped
+    fn exit(&self) {
+        *self.lock() = WorkerRequest::Exited;
+    }
+
+    fn running(&self) -> Option<u64> {
+        match *self.lock() {
+            WorkerRequest::Running(request) => Some(request),
+            WorkerRequest::Idle | WorkerRequest::Exited => None,
+        }
+    }
+
+    /// Send `signal` to the process if it still handles `request`
+    fn send(&self, request: u64, signal: libc::c_int) -> bool {
+        let running = self.lock();
+
+        if *running != WorkerRequest::Running(request) {
+            return false;
+        }
+
+        // SAFETY: the process is not reaped while the lock is held, so the pid is still its own
+        unsafe { libc::kill(self.pid, signal) == 0 }
+    }
+}
+
+impl PyWorkerProcess {
+    fn spawn() -> error::Result<Self> {
+        static NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
+
+        let config = get_config_element::<config::Python>()?;
+        let python = config.python_executable.clone();
+        let generation = script_generation();
+
+        let mut child = Command::new(&python)
+            .arg("-c")
+            .arg(WORKER_SCRIPT)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .context(error::CannotStartPythonWorker { python })?;
+
+        let signals = PyWorkerSignals::new(child.id());
+        let requests = child.stdin.take().expect("stdin is piped");
+        let replies = child.stdout.take().expect("stdout is piped");
+
+        let name = format!(
+            "geoengine-python-{}-{}",
+            std::process::id(),
+            NEXT_WORKER.fetch_add(1, Ordering::Relaxed)
+        );
+        let shared_memory = shared_memory_dir();
+
+        let mut process = Self {
+            child,
+            requests,
+            replies,
+            input: shared_memory.join(format!("{}-in", name)),
+            output: shared_memory.join(format!("{}-out", name)),
+            generation,
+            signals,
+        };
+
+        process.prepare(&config)?;
+
+        Ok(process)
+    }
+
+    /// Apply the `[python]` settings like `prepare_interpreter` does for the embedded interpreter
+    fn prepare(&mut self, config: &config::Python) -> error::Result<()> {
+        let mut paths = config
+            .extra_sys_path
+            .iter()
+            .map(PathBuf::as_path)
+            .collect::<Vec<_>>();
+        paths.push(&config.script_root);
+
+        let request = Request::Prepare {
+            paths,
+            virtualenv: config.virtualenv.as_deref(),
+            preload_modules: &config.preload_modules,
+        };
+        let reply = self.request(&request, None)?;
+
+        if let (Some(virtualenv), Some(python_version)) = (&config.virtualenv, reply.python_version)
+        {
+            return Err(Error::InvalidVirtualenv {
+                path: virtualenv.clone(),
+                python_version,
+            });
+        }
+
+        match reply.missing_modules {
+            Some(modules) if !modules.is_empty() => Err(Error::MissingPythonModules { modules }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Send `request` and wait for the reply, failed requests become errors
+    ///
+    /// A process that does not start to reply within `timeout` is killed.
+    fn request(&mut self, request: &Request, timeout: Option<Duration>) -> error::Result<Reply> {
+        let message = serde_json::to_vec(request).expect("requests are serializable");
+
+        self.signals.start();
+        let reply = self.exchange(&message, timeout);
+        self.signals.finish();
+
+        match reply {
+            Ok(reply) if reply.ok => Ok(reply),
+            Ok(Reply {
+                exceeded: Some(limit),
+                ..
+            }) => Err(Error::ResourceLimitExceeded { limit }),
+            Ok(reply) => Err(Error::Python {
+                exception_type: reply.exception_type.unwrap_or_default(),
+                message: reply.message.unwrap_or_default(),
+                traceback: reply.traceback.unwrap_or_default(),
+            }),
+            Err(_) => {
+                // the pipes only break if the process is gone
+                self.signals.exit();
+                let status = self
+                    .child
+                    .wait()
+                    .map_or_else(|error| error.to_string(), |status| status.to_string());
+
+                Err(Error::PythonWorkerCrashed { status })
+            }
+        }
+    }
+
+    fn exchange(&mut self, message: &[u8], timeout: Option<Duration>) -> std::io::Result<Reply> {
+        write_frame(&mut self.requests, message)?;
+
+        if let Some(timeout) = timeout {
+            if !self.replies_within(timeout) {
+                // reading the reply fails once the process is gone
+                warn!(
+                    "killed a Python worker process that did not answer within {:?}",
+                    timeout
+                );
+                self.child.kill()?;
+            }
+        }
+
+        let frame = read_frame(&mut self.replies)?;
+        serde_json::from_slice::<Reply>(&frame)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+    }
+
+    /// Whether the process starts to reply, or exits, within `timeout`
+    fn replies_within(&self, timeout: Duration) -> bool {
+        let mut replies = libc::pollfd {
+            fd: self.replies.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+
+        // SAFETY: a single pollfd of an open file descriptor is passed, errors are left to the
+        // following read
+        unsafe { libc::poll(&mut replies, 1, timeout_ms) != 0 }
+    }
+}
+
+impl Drop for PyWorkerProcess {
+    fn drop(&mut self) {
+        // the process may have exited already, so failures are expected here
+        self.signals.exit();
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.input);
+        let _ = std::fs::remove_file(&self.output);
+    }
+}
+
+/// Files in `/dev/shm` never touch the disk on Linux, elsewhere the temp directory is used
+fn shared_memory_dir() -> PathBuf {
+    let shm = Path::new("/dev/shm");
+
+    if shm.is_dir() {
+        shm.to_path_buf()
+    } else {
+        std::env::temp_dir()
+    }
+}
+
+fn write_frame(writer: &mut impl Write, message: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&(message.len() as u32).to_be_bytes())?;
+    writer.write_all(message)?;
+    writer.flush()
+}
+
+fn read_frame(reader: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut length = [0; 4];
+    reader.read_exact(&mut length)?;
+
+    let mut frame = vec![0; u32::from_be_bytes(length) as usize];
+    reader.read_exact(&mut frame)?;
+
+    Ok(frame)
+}
+
+lazy_static! {
+    static ref IDLE_WORKERS: Mutex<Vec<PyWorkerProcess>> = Mutex::new(Vec::new());
+}
+
+/// Start a worker process ahead of the first query and keep it idle, if there is room
+///
+/// This reports a broken environment of the worker processes, e.g., missing modules, at startup.
+pub fn start_idle_worker() -> error::Result<()> {
+    let process = PyWorkerProcess::spawn()?;
+    let max_idle_workers = get_config_element::<config::Python>()?.subprocess_workers;
+
+    if let Ok(mut idle) = IDLE_WORKERS.lock() {
+        if idle.len() < max_idle_workers {
+            idle.push(process);
+        }
+    }
+
+    Ok(())
+}
+
+/// Stop the idle worker processes, e.g., because the modules that they imported changed
+pub fn discard_idle_workers() {
+    if let Ok(mut idle) = IDLE_WORKERS.lock() {
+        idle.clear();
+    }
+}
+
+/// A worker process that has a script loaded for one query
+///
+/// When the lease is dropped, the script's state is torn down and the process goes back to the
+/// pool of idle workers, unless it crashed in the meantime.
+pub struct PyWorkerLease {
+    process: Option<PyWorkerProcess>,
+    signals: PyWorkerSignals,
+    teardown: Option<String>,
+    timeout: Option<Duration>,
+}
+
+impl PyWorkerLease {
+    /// Take an idle worker or start a new one and load `script` into it
+    pub fn acquire(script: &LoadedScript, hooks: &PyHooks) -> error::Result<Self> {
+        let mut lease = Self::reserve()?;
+        lease.load(script, hooks)?;
+
+        Ok(lease)
+    }
+
+    /// Take an idle worker or start a new one
+    fn reserve() -> error::Result<Self> {
+        let idle = IDLE_WORKERS.lock().ok().and_then(|mut idle| idle.pop());
+
+        let process = match idle {
+            Some(process) => process,
+            None => PyWorkerProcess::spawn()?,
+        };
+
+        Ok(Self {
+            signals: process.signals.clone(),
+            process: Some(process),
+            teardown: None,
+            timeout: None,
+        })
+    }
+
+    fn load(&mut self, script: &LoadedScript, hooks: &PyHooks) -> error::Result<()> {
+        let reply = self.request(&Request::Load {
+            code: &script.code,
+            file_name: &script.file_name,
+            module_name: &script.module_name,
+            hooks: hooks.names().collect(),
+        })?;
+
+        if let Some(function) = reply.missing {
+            return Err(Error::MissingPythonFunction {
+                module: script.module_name.clone(),
+                function,
+            });
+        }
+
+        self.teardown = hooks.teardown.clone();
+
+        Ok(())
+    }
+
+    /// Interrupt calls of the fit and tile hooks that take longer than `timeout`
+    ///
+    /// The worker raises `KeyboardInterrupt` in the script by means of a timer signal, which
+    /// requires a Unix system.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Create the script's state, either by calling `setup` or by loading a saved `model`
+    ///
+    /// The `limits` apply until the lease is dropped.
+    pub fn start(
+        &mut self,
+        parameters: &serde_json::Value,
+        setup: Option<&str>,
+        model: Option<&Path>,
+        limits: &PyResourceLimits,
+    ) -> error::Result<()> {
+        self.request(&Request::Start {
+            parameters,
+            setup,
+            model,
+            limits,
+        })?;
+
+        Ok(())
+    }
+
+    /// Pickle the script's state to the file of `model`
+    pub fn save(&mut self, model: &Path) -> error::Result<()> {
+        self.request(&Request::Save { model })?;
+
+        Ok(())
+    }
+
+    /// Pass `tiles` to the `fit` hook
+    pub fn fit<TIn: Pixel>(
+        &mut self,
+        fit: &str,
+        tiles: &[RasterTile2D<TIn>],
+        metadata: &TileMetadata,
+    ) -> error::Result<()> {
+        self.call(fit, tiles, metadata, None)?;
+
+        Ok(())
+    }
+
+    /// Pass `tiles` to the `tile` hook and read back its output data
+    pub fn transform<TIn: Pixel, TOut: Pixel>(
+        &mut self,
+        tile: &str,
+        tiles: &[RasterTile2D<TIn>],
+        metadata: &TileMetadata,
+        no_data_value: Option<TOut>,
+    ) -> error::Result<Vec<TOut>> {
+        let output = self.process()?.output.clone();
+        let output_no_data = no_data_value.map(pixel_to_f64);
+        let reply = self.call(tile, tiles, metadata, Some((&output, output_no_data)))?;
+
+        if let Some(found) = reply.missing_no_data {
+            return Err(missing_no_data_value(found));
+        }
+
+        let [rows, columns] = tiles[0].grid_array.shape.shape_array;
+        let dtype = numpy_dtype(TOut::TYPE);
+
+        if reply.shape.as_deref() != Some(&[rows, columns]) || reply.dtype.as_deref() != Some(dtype)
+        {
+            return Err(Error::InvalidPythonOutput {
+                expected: format!("a {} array of shape {:?}", dtype, [rows, columns]),
+                found: format!(
+                    "a {} array of shape {:?}",
+                    reply.dtype.unwrap_or_default(),
+                    reply.shape.unwrap_or_default()
+                ),
+            });
+        }
+
+        // the output is read straight into the buffer of the new tile
+        std::fs::File::open(&output)
+            .and_then(|mut file| read_pixels(&mut file, rows * columns))
+            .context(error::CannotExchangeTiles { path: output })
+    }
+
+    fn call<TIn: Pixel>(
+        &mut self,
+        hook: &str,
+        tiles: &[RasterTile2D<TIn>],
+        metadata: &TileMetadata,
+        output: Option<(&Path, Option<f64>)>,
+    ) -> error::Result<Reply> {
+        let input = self.process()?.input.clone();
+        let [rows, columns] = tiles[0].grid_array.shape.shape_array;
+
+        let mut data =
+            Vec::with_capacity(tiles.len() * rows * columns * std::mem::size_of::<TIn>());
+        for tile in tiles {
+            data.extend_from_slice(pixels_as_bytes(&tile.grid_array.data));
+        }
+        std::fs::write(&input, data).context(error::CannotExchangeTiles {
+            path: input.clone(),
+        })?;
+
+        let reply = self.request(&Request::Call {
+            hook,
+            input: &input,
+            shape: [tiles.len(), rows, columns],
+            dtype: numpy_dtype(TIn::TYPE),
+            no_data: tiles
+                .iter()
+                .map(|tile| tile.grid_array.no_data_value.map(pixel_to_f64))
+                .collect(),
+            metadata,
+            output: output.map(|(path, _)| path),
+            output_no_data: output.and_then(|(_, no_data_value)| no_data_value),
+            timeout_ms: self.timeout.map(|timeout| timeout.as_millis()),
+        });
+
+        match (reply, self.timeout) {
+            (Err(Error::Python { exception_type, .. }), Some(timeout))
+                if exception_type == "KeyboardInterrupt" =>
+            {
+                Err(Error::PythonTimeout { timeout })
+            }
+            (reply, _) => reply,
+        }
+    }
+
+    fn process(&mut self) -> error::Result<&mut PyWorkerProcess> {
+        self.process.as_mut().ok_or(Error::PythonWorkerFailed)
+    }
+
+    fn request(&mut self, request: &Request) -> error::Result<Reply> {
+        let reply = self.process()?.request(request, None);
+
+        // a crashed process is dropped so it is never reused
+        if let Err(Error::PythonWorkerCrashed { .. }) = reply {
+            self.process = None;
+        }
+
+        reply
+    }
+}
+
+impl Drop for PyWorkerLease {
+    fn drop(&mut self) {
+        let process = match self.process.take() {
+            Some(process) => process,
+            None => return,
+        };
+
+        let teardown = self.teardown.take();
+        let timeout = self
+            .timeout
+            .map_or(STOP_TIMEOUT, |timeout| timeout + INTERRUPT_GRACE);
+
+        // the teardown hook may take a while, which the dropping thread, e.g., one of the async
+        // runtime, must not wait for
+        spawn_python(move || release(process, teardown.as_deref(), timeout));
+    }
+}
+
+/// Tear down the script's state and put the process back into the pool of idle workers
+///
+/// A teardown that does not finish within `timeout` is killed with its process.
+fn release(mut process: PyWorkerProcess, teardown: Option<&str>, timeout: Duration) {
+    if let Err(error) = process.request(&Request::Stop { teardown }, Some(timeout)) {
+        // a failed teardown may leave anything behind, so the process is not reused
+        error!("cannot stop a Python worker process: {}", error);
+        return;
+    }
+
+    let max_idle_workers = get_config_element::<config::Python>()
+        .map(|config| config.subprocess_workers)
+        .unwrap_or(0);
+
+    if let Ok(mut idle) = IDLE_WORKERS.lock() {
+        if idle.len() < max_idle_workers && process.generation == script_generation() {
+            idle.push(process);
+        }
+    }
+}
+
+/// The name of the numpy dtype of a raster data type
+fn numpy_dtype(data_type: RasterDataType) -> &'static str {
+    match data_type {
+        RasterDataType::U8 => "uint8",
+        RasterDataType::U16 => "uint16",
+        RasterDataType::U32 => "uint32",
+        RasterDataType::U64 => "uint64",
+        RasterDataType::I8 => "int8",
+        RasterDataType::I16 => "int16",
+        RasterDataType::I32 => "int32",
+        RasterDataType::I64 => "int64",
+        RasterDataType::F32 => "float32",
+        RasterDataType::F64 => "float64",
+    }
+}
+
+fn pixels_as_bytes<T: Pixel>(pixels: &[T]) -> &[u8] {
+    // SAFETY: pixels are plain numbers without padding, so all bytes of the slice are initialized
+    unsafe {
+        std::slice::from_raw_parts(pixels.as_ptr().cast::<u8>(), std::mem::size_of_val(pixels))
+    }
+}
+
+/// Read exactly `len` pixels, which fails if `reader` has fewer or more bytes
+fn read_pixels<T: Pixel>(reader: &mut impl Read, len: usize) -> std::io::Result<Vec<T>> {
+    let mut pixels = Vec::<T>::with_capacity(len);
+
+    // SAFETY: the vector has room for `len` pixels, which are zeroed first, and every bit pattern
+    // is a valid pixel value
+    let bytes = unsafe {
+        std::ptr::write_bytes(pixels.as_mut_ptr(), 0, len);
+        pixels.set_len(len);
+        std::slice::from_raw_parts_mut(
+            pixels.as_mut_ptr().cast::<u8>(),
+            len * std::mem::size_of::<T>(),
+        )
+    };
+
+    reader.read_exact(bytes)?;
+
+    if reader.read(&mut [0])? != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("the output has more than {} pixels", len),
+        ));
+    }
+
+    Ok(pixels)
+}
+
+/// How long an interrupted worker process may take to answer before it is killed
+const INTERRUPT_GRACE: Duration = Duration::from_secs(1);
+
+/// How long the teardown of a lease without a timeout may take before its process is killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interrupts the request that a worker process handles when dropped
+///
+/// The script gets a `KeyboardInterrupt` first. A process that does not answer within
+/// `INTERRUPT_GRACE`, e.g., because it is stuck in native code, is killed, which ends its lease.
+struct InterruptOnDrop(PyWorkerSignals);
+
+impl Drop for InterruptOnDrop {
+    fn drop(&mut self) {
+        let request = match self.0.running() {
+            Some(request) => request,
+            None => return,
+        };
+
+        self.0.send(request, libc::SIGINT);
+
+        // the dropping thread may belong to the async runtime, so the grace period is waited out
+        // on a thread of its own
+        let signals = self.0.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(INTERRUPT_GRACE);
+
+            if signals.send(request, libc::SIGKILL) {
+                warn!("killed a Python worker process that ignored an interrupt");
+            }
+        });
+    }
+}
+
+/// Run `job`, which makes requests to the worker process of `signals`, on the blocking threads of
+/// the async runtime
+///
+/// The requests only wait for the worker process, so they do not take up the Python worker pool,
+/// which would limit how many processes work at once.
+///
+/// A request that is still running when the returned future is dropped or `timeout` expires is
+/// interrupted. The worker process interrupts timed out hooks itself, so the timeout only catches
+/// the ones that do not answer in time.
+async fn run_request<T, F>(
+    signals: &PyWorkerSignals,
+    timeout: Option<Duration>,
+    job: F,
+) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let _interrupt = InterruptOnDrop(signals.clone());
+
+    match timeout.map(|timeout| timeout + INTERRUPT_GRACE) {
+        Some(timeout) => tokio::time::timeout(timeout, run_blocking(job))
+            .await
+            .map_err(|_| Error::PythonTimeout { timeout })?,
+        None => run_blocking(job).await,
+    }
+}
+
+/// Run `job` on the blocking threads of the async runtime and wait asynchronously for its result
+async fn run_blocking<T, F>(job: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    // a panicking job is reported like on the Python worker pool
+    tokio::task::spawn_blocking(job)
+        .await
+        .unwrap_or_else(|_| Err(Error::PythonWorkerFailed.into()))
+}
+
+/// Runs the hooks of a script in a worker process, with the same phases as the `PyRunner`
+pub struct PySubprocessRunner<TIn>
+where
+    TIn: Pixel,
+{
+    rasters: Vec<Box<dyn RasterQueryProcessor<RasterType = TIn>>>,
+    script: Arc<LoadedScript>,
+    hooks: Arc<PyHooks>,
+    parameters: Arc<serde_json::Value>,
+    spatial_reference: SpatialReferenceOption,
+    model: Option<Arc<PyModel>>,
+    timeout: Option<Duration>,
+    limits: PyResourceLimits,
+    fit_buffer_bytes: Option<usize>,
+    output_no_data_value: Option<f64>,
+}
+
+impl<TIn> PySubprocessRunner<TIn>
+where
+    TIn: Pixel,
+{
+    pub fn new(
+        rasters: Vec<Box<dyn RasterQueryProcessor<RasterType = TIn>>>,
+        script: LoadedScript,
+        hooks: PyHooks,
+        parameters: serde_json::Value,
+        spatial_reference: SpatialReferenceOption,
+    ) -> Self {
+        Self {
+            rasters,
+            script: Arc::new(script),
+            hooks: Arc::new(hooks),
+            parameters: Arc::new(parameters),
+            spatial_reference,
+            model: None,
+            timeout: None,
+            limits: PyResourceLimits::default(),
+            fit_buffer_bytes: None,
+            output_no_data_value: None,
+        }
+    }
+
+    /// Load or save the fitted state of the script
+    pub fn with_model(mut self, model: Option<Arc<PyModel>>) -> Self {
+        self.model = model;
+        self
+    }
+
+    /// Interrupt calls of the fit and tile hooks that take longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Restrict the resources of the worker process during a query
+    pub fn with_limits(mut self, limits: PyResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Keep up to `bytes` of fitted tiles for the transform phase, see `FitBuffer`
+    pub fn with_fit_buffer(mut self, bytes: Option<usize>) -> Self {
+        self.fit_buffer_bytes = bytes;
+        self
+    }
+
+    /// Use `no_data_value` for the output tiles instead of the input no-data value
+    pub fn with_output_no_data_value(mut self, no_data_value: Option<f64>) -> Self {
+        self.output_no_data_value = no_data_value;
+        self
+    }
+
+    /// Run the script on all tiles of the query in a worker process that is leased for the
+    /// whole query
+    ///
+    /// Like `PyRunner::query`, the sources are queried a second time after a training phase,
+    /// unless the fitted tiles fit into the fit buffer.
+    /// If the process crashes, the query ends with an error and the next query starts a new one.
+    /// Dropping the stream interrupts the running call, see `InterruptOnDrop`, before the worker
+    /// process is released.
+    pub fn query<'a, TOut>(
+        &'a self,
+        query: QueryRectangle,
+        ctx: &'a dyn QueryContext,
+    ) -> BoxStream<'a, Result<RasterTile2D<TOut>>>
+    where
+        TOut: Pixel,
+    {
+        futures::stream::once(async move {
+            let (lease, signals) = self.lease().await?;
+
+            let fits = self.model.as_ref().map_or(true, |model| model.fits());
+
+            let mut fitted = None;
+
+            if let Some(fit) = self.hooks.fit.as_ref().filter(|_| fits) {
+                let mut buffer = FitBuffer::new(self.fit_buffer_bytes);
+
+                self.query_zipped(query, ctx)?
+                    .try_for_each(|raster_tiles| {
+                        buffer.push(&raster_tiles);
+
+                        let lease = lease.clone();
+                        let fit = fit.clone();
+                        let metadata =
+                            TileMetadata::new(&raster_tiles, self.spatial_reference, &query);
+
+                        run_request(&signals, self.timeout, move || {
+                            Ok(lock(&lease)?.fit(&fit, &raster_tiles, &metadata)?)
+                        })
+                    })
+                    .await?;
+
+                fitted = buffer.into_tiles();
+            }
+
+            if let Some(model) = self.model.clone().filter(|model| model.saves()) {
+                let lease = lease.clone();
+
+                run_request(
+                    &signals,
+                    None,
+                    move || Ok(lock(&lease)?.save(model.path())?),
+                )
+                .await?;
+            }
+
+            let raster_tiles = match fitted {
+                Some(tiles) => futures::stream::iter(tiles).map(Ok).boxed(),
+                None => self.query_zipped(query, ctx)?,
+            };
+
+            let tiles = raster_tiles.then(move |raster_tiles| {
+                let lease = lease.clone();
+                let signals = signals.clone();
+                let tile = self.hooks.tile.clone();
+
+                async move {
+                    let raster_tiles = raster_tiles?;
+                    let metadata = TileMetadata::new(&raster_tiles, self.spatial_reference, &query);
+                    let no_data_value =
+                        output_no_data_value(&raster_tiles[0], self.output_no_data_value);
+
+                    run_request(&signals, self.timeout, move || {
+                        let data = lock(&lease)?.transform(
+                            &tile,
+                            &raster_tiles,
+                            &metadata,
+                            no_data_value,
+                        )?;
+
+                        output_tile(&raster_tiles[0], data, no_data_value)
+                    })
+                    .await
+                }
+            });
+
+            Ok(tiles.boxed())
+        })
+        .map(|tiles| match tiles {
+            Ok(tiles) => tiles,
+            Err(error) => futures::stream::once(async { Err(error) }).boxed(),
+        })
+        .flatten()
+        .boxed()
+    }
+
+    /// Lease a worker process and start the script's state in it
+    ///
+    /// Loading the script and its setup are interrupted like the calls of the hooks.
+    async fn lease(&self) -> Result<(Arc<Mutex<PyWorkerLease>>, PyWorkerSignals)> {
+        let lease = run_blocking(|| Ok(PyWorkerLease::reserve()?)).await?;
+        let signals = lease.signals.clone();
+        let lease = Arc::new(Mutex::new(lease.with_timeout(self.timeout)));
+
+        let script = self.script.clone();
+        let hooks = self.hooks.clone();
+        let parameters = self.parameters.clone();
+        let model = self.model.clone();
+        let limits = self.limits;
+        let started = lease.clone();
+
+        run_request(&signals, self.timeout, move || {
+            let mut lease = lock(&started)?;
+            lease.load(&script, &hooks)?;
+
+            let saved_model = model
+                .as_ref()
+                .filter(|model| !model.fits())
+                .map(|model| model.path());
+            lease.start(&parameters, hooks.setup.as_deref(), saved_model, &limits)?;
+
+            Ok(())
+        })
+        .await?;
+
+        Ok((lease, signals))
+    }
+
+    /// Query all input rasters and combine their tiles
+    fn query_zipped<'a>(
+        &'a self,
+        query: QueryRectangle,
+        ctx: &'a dyn QueryContext,
+    ) -> Result<BoxStream<'a, Result<Vec<RasterTile2D<TIn>>>>> {
+        let streams = self
+            .rasters
+            .iter()
+            .map(|raster| raster.query(query, ctx))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(zip_tile_streams(streams))
+    }
+}
+
+fn lock(lease: &Mutex<PyWorkerLease>) -> error::Result<MutexGuard<PyWorkerLease>> {
+    lease.lock().map_err(|_| Error::PythonWorkerFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::PyScript;
+    use crate::test_util::{query_rectangle, tile_u8, CountingProcessor};
+    use geoengine_datatypes::spatial_reference::SpatialReference;
+    use geoengine_operators::engine::MockQueryContext;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn frames_messages() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, br#"{"ok": true}"#).unwrap();
+
+        assert_eq!(&buffer[..4], &[0, 0, 0, 12]);
+        assert_eq!(
+            read_frame(&mut buffer.as_slice()).unwrap(),
+            br#"{"ok": true}"#
+        );
+    }
+
+    #[test]
+    fn workers_report_all_missing_modules() {
+        let mut process = PyWorkerProcess::spawn().unwrap();
+
+        let modules = ["json", "no_such_module", "math", "no_such_module_either"]
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        let reply = process
+            .request(
+                &Request::Prepare {
+                    paths: vec![Path::new("/first")],
+                    virtualenv: None,
+                    preload_modules: &modules,
+                },
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(
+            reply.missing_modules.unwrap(),
+            vec!["no_such_module", "no_such_module_either"]
+        );
+    }
+
+    #[test]
+    fn converts_pixels_to_bytes_and_back() {
+        let pixels = vec![1.5_f32, -2., 1e10];
+        let bytes = pixels_as_bytes(&pixels);
+
+        assert_eq!(bytes.len(), 12);
+        assert_eq!(read_pixels::<f32>(&mut &bytes[..], 3).unwrap(), pixels);
+        assert!(read_pixels::<f32>(&mut &bytes[..], 4).is_err());
+        assert!(read_pixels::<f32>(&mut &bytes[..], 2).is_err());
+    }
+
+    #[test]
+    fn deserializes_limits() {
+        assert_eq!(
+            serde_json::from_str::<PyResourceLimits>(r#"{"memory_mb": 512}"#).unwrap(),
+            PyResourceLimits {
+                memory_mb: Some(512),
+                cpu_time_ms: None,
+            }
+        );
+        assert!(serde_json::from_str::<PyResourceLimits>("{}")
+            .unwrap()
+            .is_unlimited());
+    }
+
+    #[test]
+    fn deserializes_backends() {
+        assert_eq!(
+            serde_json::from_str::<PyBackend>(r#""subprocess""#).unwrap(),
+            PyBackend::Subprocess
+        );
+        assert_eq!(
+            serde_json::from_str::<PyBackend>(r#""inProcess""#).unwrap(),
+            PyBackend::InProcess
+        );
+    }
+
+    #[tokio::test]
+    async fn buffered_fit_tiles_are_not_queried_again() {
+        let tiles = vec![
+            tile_u8([0, 0], [2, 2], vec![1, 2, 3, 4]),
+            tile_u8([0, 1], [2, 2], vec![5, 6, 7, 8]),
+        ];
+
+        // the two tiles have 8 bytes of pixels
+        for &(fit_buffer_bytes, expected_queries) in &[(Some(8), 1), (Some(7), 2), (None, 2)] {
+            let processor = CountingProcessor::new(tiles.clone());
+            let queries = processor.queries.clone();
+
+            let script = PyScript::Source(
+                "def fit(data, **kwargs):\n    pass\n\ndef tile(data, **kwargs):\n    return data[0]\n"
+                    .to_string(),
+            )
+            .load("fit_buffer")
+            .unwrap();
+
+            let runner = PySubprocessRunner::new(
+                vec![processor.boxed()],
+                script,
+                PyHooks {
+                    tile: "tile".to_string(),
+                    fit: Some("fit".to_string()),
+                    setup: None,
+                    teardown: None,
+                },
+                serde_json::Value::Null,
+                SpatialReference::epsg_4326().into(),
+            )
+            .with_fit_buffer(fit_buffer_bytes);
+
+            let result = runner
+                .query::<u8>(query_rectangle(), &MockQueryContext::new(0))
+                .map(|tile| tile.unwrap())
+                .collect::<Vec<_>>()
+                .await;
+
+            assert_eq!(result, tiles);
+            assert_eq!(queries.load(Ordering::SeqCst), expected_queries);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+# Runs the hooks of a Python operator script in a separate process.
+#
+# Messages are JSON objects, framed by their length as a 4 byte big-endian integer. They are read
+# from stdin and answered on the original stdout, while anything the script prints goes to stderr.
+# Tile data is not part of the messages but exchanged through files in shared memory.
+
+import importlib
+import json
+import os
+import pickle
+import resource
+import signal
+import site
+import struct
+import sys
+import traceback
+import types
+from contextlib import contextmanager
+
+import numpy as np
+
+
+def read_message(channel):
+    header = channel.read(4)
+    if len(header) < 4:
+        return None
+
+    (length,) = struct.unpack('>I', header)
+    return json.loads(channel.read(length).decode('utf-8'))
+
+
+def write_message(channel, message):
+    data = json.dumps(message).encode('utf-8')
+    channel.write(struct.pack('>I', len(data)) + data)
+    channel.flush()
+
+
+def raise_interrupt(signum, frame):
+    raise KeyboardInterrupt('the call timed out')
+
+
+@contextmanager
+def interruptible():
+    """Turn `SIGINT` into `KeyboardInterrupt` in the block, it is ignored elsewhere
+
+    The server interrupts a request this way, e.g., because its query was dropped. A late signal
+    must neither break the reply nor end the worker while it waits for the next request.
+    """
+    signal.signal(signal.SIGINT, signal.default_int_handler)
+    try:
+        yield
+    finally:
+        signal.signal(signal.SIGINT, signal.SIG_IGN)
+
+
+@contextmanager
+def interrupt_after(timeout_ms):
+    """Raise `KeyboardInterrupt` in the block if it takes longer than `timeout_ms`"""
+    if timeout_ms is None:
+        yield
+        return
+
+    previous = signal.signal(signal.SIGALRM, raise_interrupt)
+    signal.setitimer(signal.ITIMER_REAL, timeout_ms / 1000)
+    try:
+        yield
+    finally:
+        signal.setitimer(signal.ITIMER_REAL, 0)
+        signal.signal(signal.SIGALRM, previous)
+
+
+class CpuTimeLimitExceeded(BaseException):
+    """Raised in the script when its query used up the CPU time limit"""
+
+
+def raise_cpu_time_limit_exceeded(signum, frame):
+    raise CpuTimeLimitExceeded()
+
+
+class Worker:
+
+    def __init__(self):
+        self.module = None
+        self.state = None
+        self.kwargs = {}
+        self.limits = {}
+        self.memory_limit = None
+        self.cpu_time = None
+
+    def limit(self, limits):
+        self.limits = limits
+
+        memory_mb = limits.get('memory_mb')
+        if memory_mb is not None:
+            self.memory_limit = resource.getrlimit(resource.RLIMIT_DATA)
+            resource.setrlimit(resource.RLIMIT_DATA,
+                               (memory_mb * 1024 * 1024, self.memory_limit[1]))
+
+        cpu_time_ms = limits.get('cpu_time_ms')
+        if cpu_time_ms is not None:
+            self.cpu_time = cpu_time_ms / 1000
+
+    def unlimit(self):
+        if self.memory_limit is not None:
+            resource.setrlimit(resource.RLIMIT_DATA, self.memory_limit)
+
+        self.limits = {}
+        self.memory_limit = None
+        self.cpu_time = None
+
+    @contextmanager
+    def limited(self):
+        """Count the CPU time of the block against the limit of the query"""
+        if self.cpu_time is None:
+            yield
+            return
+
+        previous = signal.signal(signal.SIGPROF, raise_cpu_time_limit_exceeded)
+        signal.setitimer(signal.ITIMER_PROF, self.cpu_time)
+        try:
+            yield
+        finally:
+            remaining, _ = signal.setitimer(signal.ITIMER_PROF, 0)
+            signal.signal(signal.SIGPROF, previous)
+            # a timer of zero is disabled, so a used up limit has to fire right away next time
+            self.cpu_time = max(remaining, 1e-6)
+
+    def exceeded(self, exception):
+        """The limit that `exception` reports as exceeded, if any"""
+        if isinstance(exception, CpuTimeLimitExceeded):
+            return '{} ms of CPU time'.format(self.limits['cpu_time_ms'])
+        if isinstance(exception, MemoryError) and self.memory_limit is not None:
+            return '{} MB of memory'.format(self.limits['memory_mb'])
+        return None
+
+    def prepare(self, paths, virtualenv=None, preload_modules=()):
+        # the same setup as `prepare_interpreter` in `interpreter.rs`
+        site_dirs = []
+        if virtualenv is not None:
+            version = '{}.{}'.format(*sys.version_info[:2])
+            candidates = [os.path.join(virtualenv, 'lib', 'python' + version, 'site-packages'),
+                          os.path.join(virtualenv, 'Lib', 'site-packages')]
+            site_dirs = [path for path in candidates if os.path.isdir(path)][:1]
+            if not site_dirs:
+                return {'python_version': version}
+
+        before = [path for path in sys.path if path not in paths]
+        for site_dir in site_dirs:
+            site.addsitedir(site_dir)
+        added = [path for path in sys.path if path not in before and path not in paths]
+        sys.path[:] = paths + added + before
+
+        missing_modules = []
+        for module in preload_modules:
+            try:
+                importlib.import_module(module)
+            except ModuleNotFoundError:
+                missing_modules.append(module)
+
+        return {'missing_modules': missing_modules}
+
+    def load(self, code, file_name, module_name, hooks):
+        module = types.ModuleType(module_name)
+        module.__file__ = file_name
+        exec(compile(code, file_name, 'exec'), module.__dict__)
+        self.module = module
+
+        for hook in hooks:
+            if not callable(getattr(module, hook, None)):
+                return {'missing': hook}
+
+        return {}
+
+    def start(self, parameters, setup=None, model=None, limits=None):
+        self.kwargs = dict(parameters or {})
+        self.limit(limits or {})
+
+        if model is not None:
+            with open(model, 'rb') as model_file:
+                self.state = pickle.load(model_file)
+        else:
+            self.state = types.SimpleNamespace()
+            if setup is not None:
+                with self.limited():
+                    getattr(self.module, setup)(state=self.state, **self.kwargs)
+
+        return {}
+
+    def save(self, model):
+        os.makedirs(os.path.dirname(model), exist_ok=True)
+        with open(model, 'wb') as model_file:
+            pickle.dump(self.state, model_file)
+        return {}
+
+    def call(self, hook, input, shape, dtype, no_data, metadata, output=None, output_no_data=None,
+             timeout_ms=None):
+        data = np.fromfile(input, dtype=dtype).reshape(shape)
+
+        mask = np.zeros(shape, dtype=bool)
+        for band, band_no_data in enumerate(no_data):
+            if band_no_data is not None:
+                mask[band] = (data[band] == band_no_data) | (
+                    np.isnan(band_no_data) & np.isnan(data[band].astype(float)))
+
+        with interrupt_after(timeout_ms), self.limited():
+            result = getattr(self.module, hook)(
+                np.ma.masked_array(data, mask=mask), state=self.state, metadata=metadata,
+                **self.kwargs)
+
+        # the fit hook has no output
+        if output is None:
+            return {}
+
+        # like `py_to_grid_data`, no-data elements are an error without an output no-data value
+        if np.ma.isMaskedArray(result):
+            masked = int(np.ma.count_masked(result))
+            if output_no_data is None and masked > 0:
+                return {'missing_no_data': '{} masked elements'.format(masked)}
+            result = result.filled(output_no_data) if output_no_data is not None else result.data
+
+        result = np.asarray(result)
+        if result.dtype.kind == 'f':
+            nans = np.isnan(result)
+            if output_no_data is None and nans.any():
+                return {'missing_no_data': '{} NaN elements'.format(int(nans.sum()))}
+            if output_no_data is not None:
+                result = np.where(nans, output_no_data, result).astype(result.dtype)
+
+        np.ascontiguousarray(result).tofile(output)
+        return {'shape': list(result.shape), 'dtype': result.dtype.name}
+
+    def stop(self, teardown=None):
+        if teardown is not None:
+            getattr(self.module, teardown)(state=self.state)
+        self.state = None
+        self.kwargs = {}
+        self.unlimit()
+        return {}
+
+
+def main():
+    requests = sys.stdin.buffer
+    replies = os.fdopen(os.dup(sys.stdout.fileno()), 'wb')
+    os.dup2(sys.stderr.fileno(), sys.stdout.fileno())
+
+    signal.signal(signal.SIGINT, signal.SIG_IGN)
+    worker = Worker()
+
+    while True:
+        request = read_message(requests)
+        if request is None:
+            return
+
+        operation = request.pop('op')
+        try:
+            with interruptible():
+                reply = getattr(worker, operation)(**request)
+            reply['ok'] = True
+        # timeouts and limits interrupt the script with exceptions that are no `Exception`s
+        except (Exception, KeyboardInterrupt, CpuTimeLimitExceeded) as exception:
+            reply = {
+                'ok': False,
+                'exceeded': worker.exceeded(exception),
+                'exception_type': type(exception).__name__,
+                'message': str(exception),
+                'traceback': ''.join(traceback.format_tb(exception.__traceback__)),
+            }
+
+        write_message(replies, reply)
+
+
+main()
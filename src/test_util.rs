@@ -0,0 +1,100 @@
+//! Fixtures that the tests of the Python operators share
+
+use futures::stream::BoxStream;
+use geoengine_datatypes::primitives::{
+    BoundingBox2D, Measurement, SpatialResolution, TimeInterval,
+};
+use geoengine_datatypes::raster::{Grid2D, RasterDataType, RasterTile2D, TileInformation};
+use geoengine_datatypes::spatial_reference::SpatialReference;
+use geoengine_operators::engine::{
+    InitializedOperator, MockExecutionContext, QueryContext, QueryRectangle, RasterOperator,
+    RasterQueryProcessor, RasterResultDescriptor,
+};
+use geoengine_operators::mock::{MockRasterSource, MockRasterSourceParams};
+use geoengine_operators::util::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A source of `u8` tiles in EPSG:4326
+pub fn mock_raster_source(tiles: Vec<RasterTile2D<u8>>) -> Box<dyn RasterOperator> {
+    MockRasterSource {
+        params: MockRasterSourceParams {
+            data: tiles,
+            result_descriptor: RasterResultDescriptor {
+                data_type: RasterDataType::U8,
+                spatial_reference: SpatialReference::epsg_4326().into(),
+                measurement: Measurement::Unitless,
+            },
+        },
+    }
+    .boxed()
+}
+
+/// A processor of the `tiles` of a `mock_raster_source` that counts how often it is queried
+///
+/// The counter is shared, so it can be read after the processor was handed to a runner.
+pub struct CountingProcessor {
+    processor: Box<dyn RasterQueryProcessor<RasterType = u8>>,
+    pub queries: Arc<AtomicUsize>,
+}
+
+impl CountingProcessor {
+    pub fn new(tiles: Vec<RasterTile2D<u8>>) -> Self {
+        let processor = mock_raster_source(tiles)
+            .initialize(&MockExecutionContext::default())
+            .unwrap()
+            .query_processor()
+            .unwrap()
+            .get_u8()
+            .unwrap();
+
+        Self {
+            processor,
+            queries: Default::default(),
+        }
+    }
+}
+
+impl RasterQueryProcessor for CountingProcessor {
+    type RasterType = u8;
+
+    fn raster_query<'a>(
+        &'a self,
+        query: QueryRectangle,
+        ctx: &'a dyn QueryContext,
+    ) -> Result<BoxStream<'a, Result<RasterTile2D<u8>>>> {
+        self.queries.fetch_add(1, Ordering::SeqCst);
+        self.processor.raster_query(query, ctx)
+    }
+}
+
+/// A tile without no data value that is valid at all times
+pub fn tile_u8(position: [isize; 2], shape: [usize; 2], data: Vec<u8>) -> RasterTile2D<u8> {
+    timed_tile_u8(TimeInterval::default(), position, shape, data)
+}
+
+pub fn timed_tile_u8(
+    time: TimeInterval,
+    position: [isize; 2],
+    shape: [usize; 2],
+    data: Vec<u8>,
+) -> RasterTile2D<u8> {
+    RasterTile2D::new_with_tile_info(
+        time,
+        TileInformation {
+            global_geo_transform: Default::default(),
+            global_tile_position: position.into(),
+            tile_size_in_pixels: shape.into(),
+        },
+        Grid2D::new(shape.into(), data, None).unwrap(),
+    )
+}
+
+/// A query of the 2x2 tiles at the origin, at a resolution of one pixel per unit
+pub fn query_rectangle() -> QueryRectangle {
+    QueryRectangle {
+        bbox: BoundingBox2D::new((0.0, 0.0).into(), (2.0, 2.0).into()).unwrap(),
+        time_interval: Default::default(),
+        spatial_resolution: SpatialResolution::new(1., 1.).unwrap(),
+    }
+}
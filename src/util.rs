@@ -0,0 +1,276 @@
+use crate::error::Error;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use geoengine_datatypes::collections::TypedFeatureCollection;
+use geoengine_datatypes::raster::{Grid2D, Pixel, Raster, RasterTile2D};
+use geoengine_operators::engine::{
+    ExecutionContext, InitializedRasterOperator, InitializedVectorOperator, QueryContext,
+    QueryProcessor, QueryRectangle, RasterOperator, RasterQueryProcessor,
+    TypedRasterQueryProcessor, TypedVectorQueryProcessor, VectorOperator,
+};
+use geoengine_operators::error::Error as GeoengineOperatorsError;
+use geoengine_operators::util::Result;
+use std::any::Any;
+
+/// Match on the processor's data type and evaluate `$body` with the unwrapped processor
+macro_rules! call_on_typed_raster_processor {
+    ($typed_processor:expr, $processor:ident => $body:expr) => {
+        match $typed_processor {
+            geoengine_operators::engine::TypedRasterQueryProcessor::U8($processor) => $body,
+            geoengine_operators::engine::TypedRasterQueryProcessor::U16($processor) => $body,
+            geoengine_operators::engine::TypedRasterQueryProcessor::U32($processor) => $body,
+            geoengine_operators::engine::TypedRasterQueryProcessor::U64($processor) => $body,
+            geoengine_operators::engine::TypedRasterQueryProcessor::I8($processor) => $body,
+            geoengine_operators::engine::TypedRasterQueryProcessor::I16($processor) => $body,
+            geoengine_operators::engine::TypedRasterQueryProcessor::I32($processor) => $body,
+            geoengine_operators::engine::TypedRasterQueryProcessor::I64($processor) => $body,
+            geoengine_operators::engine::TypedRasterQueryProcessor::F32($processor) => $body,
+            geoengine_operators::engine::TypedRasterQueryProcessor::F64($processor) => $body,
+        }
+    };
+}
+
+/// Create the query processors of all `$raster_sources` and evaluate `$body` with them bound to
+/// `$rasters` as a `Vec` of processors of the first source's pixel type
+macro_rules! call_on_raster_processors {
+    ($raster_sources:expr, $rasters:ident => $body:expr) => {{
+        let mut typed_processors = $raster_sources
+            .iter()
+            .map(|raster| raster.query_processor())
+            .collect::<geoengine_operators::util::Result<Vec<_>>>()?;
+        let first_processor = typed_processors.remove(0);
+
+        $crate::util::call_on_typed_raster_processor!(first_processor, p => {
+            let $rasters = std::iter::once(p)
+                .chain(typed_processors.into_iter().map(|typed| {
+                    $crate::util::raster_processor_of_type(typed).expect("checked")
+                }))
+                .collect::<Vec<_>>();
+
+            $body
+        })
+    }};
+}
+
+pub(crate) use call_on_raster_processors;
+pub(crate) use call_on_typed_raster_processor;
+
+/// Initialize the sources of a Python operator and check that all raster inputs are compatible
+pub fn initialize_sources(
+    raster_sources: Vec<Box<dyn RasterOperator>>,
+    vector_sources: Vec<Box<dyn VectorOperator>>,
+    context: &dyn ExecutionContext,
+) -> Result<(
+    Vec<Box<InitializedRasterOperator>>,
+    Vec<Box<InitializedVectorOperator>>,
+)> {
+    if raster_sources.is_empty() {
+        return Err(GeoengineOperatorsError::InvalidNumberOfRasterInputs {
+            expected: 1..usize::MAX,
+            found: 0,
+        });
+    }
+
+    let initialized_rasters = raster_sources
+        .into_iter()
+        .map(|raster| raster.initialize(context))
+        .collect::<Result<Vec<_>>>()?;
+
+    let initialized_vectors = vector_sources
+        .into_iter()
+        .map(|vector| vector.initialize(context))
+        .collect::<Result<Vec<_>>>()?;
+
+    let input_descriptor = initialized_rasters[0].result_descriptor();
+    for raster in &initialized_rasters[1..] {
+        let descriptor = raster.result_descriptor();
+        if descriptor.data_type != input_descriptor.data_type
+            || descriptor.spatial_reference != input_descriptor.spatial_reference
+        {
+            return Err(Error::IncompatibleRasterInputs {
+                reason: format!(
+                    "all inputs must have the data type {:?} and the spatial reference {}",
+                    input_descriptor.data_type, input_descriptor.spatial_reference
+                ),
+            }
+            .into());
+        }
+    }
+
+    Ok((initialized_rasters, initialized_vectors))
+}
+
+/// Unwrap a typed processor if its pixel type is `T`
+pub fn raster_processor_of_type<T: Pixel>(
+    typed_processor: TypedRasterQueryProcessor,
+) -> Option<Box<dyn RasterQueryProcessor<RasterType = T>>> {
+    let processor: Box<dyn Any> =
+        call_on_typed_raster_processor!(typed_processor, p => Box::new(p));
+
+    processor
+        .downcast::<Box<dyn RasterQueryProcessor<RasterType = T>>>()
+        .ok()
+        .map(|processor| *processor)
+}
+
+/// Combine the tile streams of several rasters into one stream of aligned tiles
+pub fn zip_tile_streams<'a, T: Pixel>(
+    streams: Vec<BoxStream<'a, Result<RasterTile2D<T>>>>,
+) -> BoxStream<'a, Result<Vec<RasterTile2D<T>>>> {
+    futures::stream::unfold(Some(streams), |streams| async move {
+        let mut streams = streams?;
+
+        let tiles = futures::future::join_all(streams.iter_mut().map(StreamExt::next)).await;
+
+        if tiles.iter().all(Option::is_none) {
+            return None;
+        }
+
+        let tiles = match tiles.into_iter().collect::<Option<Vec<_>>>() {
+            Some(tiles) => tiles.into_iter().collect::<Result<Vec<_>>>(),
+            None => Err(Error::UnalignedRasterInputs {
+                reason: "the inputs have different numbers of tiles".to_string(),
+            }
+            .into()),
+        };
+
+        match tiles.and_then(check_tiles_aligned) {
+            Ok(tiles) => Some((Ok(tiles), Some(streams))),
+            // stop after the first error as the inputs are out of step
+            Err(error) => Some((Err(error), None)),
+        }
+    })
+    .boxed()
+}
+
+fn check_tiles_aligned<T: Pixel>(tiles: Vec<RasterTile2D<T>>) -> Result<Vec<RasterTile2D<T>>> {
+    let first = &tiles[0];
+
+    for tile in &tiles[1..] {
+        if tile.time != first.time
+            || tile.tile_position != first.tile_position
+            || tile.grid_array.shape != first.grid_array.shape
+        {
+            return Err(Error::UnalignedRasterInputs {
+                reason: format!(
+                    "tile {:?} at {:?} does not match tile {:?} at {:?}",
+                    tile.tile_position, tile.time, first.tile_position, first.time
+                ),
+            }
+            .into());
+        }
+    }
+
+    Ok(tiles)
+}
+
+/// Keeps the aligned tiles of a training phase for the transform phase, up to a limit of pixel
+/// bytes
+///
+/// The tiles are moved into Python when they are fitted, so the buffer keeps clones of them. If
+/// the tiles of a query exceed the limit, the buffer gives up and the transform phase has to query
+/// the sources again.
+pub struct FitBuffer<T: Pixel> {
+    tiles: Option<Vec<Vec<RasterTile2D<T>>>>,
+    bytes: usize,
+    limit: usize,
+}
+
+impl<T: Pixel> FitBuffer<T> {
+    /// A buffer of up to `limit` bytes, or one that keeps nothing without a limit
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            tiles: limit.map(|_| Vec::new()),
+            bytes: 0,
+            limit: limit.unwrap_or(0),
+        }
+    }
+
+    /// Keep a clone of the aligned `tiles` if they fit into the limit
+    pub fn push(&mut self, tiles: &[RasterTile2D<T>]) {
+        let buffered = match &mut self.tiles {
+            Some(buffered) => buffered,
+            None => return,
+        };
+
+        self.bytes += tiles
+            .iter()
+            .map(|tile| tile.grid_array.data.len() * std::mem::size_of::<T>())
+            .sum::<usize>();
+
+        if self.bytes > self.limit {
+            self.tiles = None;
+        } else {
+            buffered.push(tiles.to_vec());
+        }
+    }
+
+    /// All tiles that were pushed, unless they exceeded the limit
+    pub fn into_tiles(self) -> Option<Vec<Vec<RasterTile2D<T>>>> {
+        self.tiles
+    }
+}
+
+/// Convert a pixel value, e.g., a no-data value, into a plain number
+pub fn pixel_to_f64<T: Pixel>(value: T) -> f64 {
+    value.as_()
+}
+
+/// The no-data value of the output tiles: the `declared` one or else the one of the input `tile`,
+/// converted to the output pixel type
+pub fn output_no_data_value<TIn: Pixel, TOut: Pixel>(
+    tile: &RasterTile2D<TIn>,
+    declared: Option<f64>,
+) -> Option<TOut> {
+    declared
+        .or_else(|| tile.grid_array.no_data_value.map(pixel_to_f64))
+        .map(TOut::from_)
+}
+
+/// Build an output tile from `data` with the metadata of the input `tile`
+pub fn output_tile<TIn: Pixel, TOut: Pixel>(
+    tile: &RasterTile2D<TIn>,
+    data: Vec<TOut>,
+    no_data_value: Option<TOut>,
+) -> Result<RasterTile2D<TOut>> {
+    Ok(RasterTile2D::new(
+        tile.time,
+        tile.tile_position,
+        tile.geo_transform(),
+        Grid2D::new(tile.grid_array.shape, data, no_data_value)?,
+    ))
+}
+
+/// Query all feature collection chunks of a vector input
+pub async fn query_feature_collections(
+    vector: &TypedVectorQueryProcessor,
+    query: QueryRectangle,
+    ctx: &dyn QueryContext,
+) -> Result<Vec<TypedFeatureCollection>> {
+    match vector {
+        TypedVectorQueryProcessor::Data(p) => {
+            p.query(query, ctx)?
+                .map_ok(TypedFeatureCollection::Data)
+                .try_collect()
+                .await
+        }
+        TypedVectorQueryProcessor::MultiPoint(p) => {
+            p.query(query, ctx)?
+                .map_ok(TypedFeatureCollection::MultiPoint)
+                .try_collect()
+                .await
+        }
+        TypedVectorQueryProcessor::MultiLineString(p) => {
+            p.query(query, ctx)?
+                .map_ok(TypedFeatureCollection::MultiLineString)
+                .try_collect()
+                .await
+        }
+        TypedVectorQueryProcessor::MultiPolygon(p) => {
+            p.query(query, ctx)?
+                .map_ok(TypedFeatureCollection::MultiPolygon)
+                .try_collect()
+                .await
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use crate::config;
+use crate::error::{self, Error};
+use geoengine_operators::util::Result;
+use geoengine_services::util::config::get_config_element;
+use lazy_static::lazy_static;
+use log::error;
+use pyo3::{ffi, Python};
+use std::cell::Cell;
+use std::os::raw::c_ulong;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+lazy_static! {
+    static ref WORKER_POOL: PyWorkerPool = PyWorkerPool::new(
+        get_config_element::<config::Python>()
+            .map(|config| config.worker_threads)
+            .unwrap_or_else(|error| {
+                error!(
+                    "cannot read `python.worker_threads`, using 1 worker thread: {}",
+                    error
+                );
+                1
+            })
+    );
+}
+
+/// A pool of threads that run all Python code, so that waiting for the GIL or a long running
+/// script never blocks the threads of the async runtime
+struct PyWorkerPool {
+    jobs: Mutex<Sender<Job>>,
+}
+
+impl PyWorkerPool {
+    fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..threads.max(1) {
+            let receiver = receiver.clone();
+
+            std::thread::Builder::new()
+                .name(format!("python-worker-{}", i))
+                .spawn(move || Self::work(&receiver))
+                .expect("the worker thread must be spawned");
+        }
+
+        Self {
+            jobs: Mutex::new(sender),
+        }
+    }
+
+    fn work(receiver: &Mutex<Receiver<Job>>) {
+        loop {
+            let job = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return,
+            };
+
+            match job {
+                // a panicking job drops its result sender, which is reported by `run_python`
+                Ok(job) => drop(std::panic::catch_unwind(AssertUnwindSafe(job))),
+                Err(_) => return,
+            }
+        }
+    }
+
+    fn submit(&self, job: Job) -> bool {
+        self.jobs
+            .lock()
+            .map(|jobs| jobs.send(job).is_ok())
+            .unwrap_or(false)
+    }
+}
+
+/// Run `job` on the Python worker pool and wait asynchronously for its result
+///
+/// If the returned future is dropped, e.g., because its query was dropped, the job is
+/// interrupted, see `run_python_with_timeout`.
+pub async fn run_python<T, F>(job: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    run_python_with_timeout(None, job).await
+}
+
+/// Run `job` on the Python worker pool and wait asynchronously for its result, for at most
+/// `timeout` after it started
+///
+/// The time that the job waits for a free worker does not count. When the time is up or the returned future is dropped, a job that has not started yet is
+/// skipped and a running one is interrupted by raising `KeyboardInterrupt` in its thread. Python
+/// only checks for the exception between two bytecode instructions, so a long call into native
+/// code, e.g., numpy, still finishes first.
+pub async fn run_python_with_timeout<T, F>(timeout: Option<Duration>, job: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    let (started_sender, started) = oneshot::channel();
+    let interrupt = InterruptOnDrop(Arc::new(Mutex::new(JobState::Waiting)));
+    let job_state = interrupt.0.clone();
+
+    let submitted = WORKER_POOL.submit(Box::new(move || {
+        // the query may have been dropped in the meantime, so nobody waits for the result
+        let _ = sender.send(run_interruptible(&job_state, started_sender, job));
+    }));
+
+    if !submitted {
+        return Err(Error::PythonWorkerFailed.into());
+    }
+
+    let result = match timeout {
+        Some(timeout) => {
+            // a job that is skipped drops the signal and reports its result right away
+            let _ = started.await;
+
+            tokio::time::timeout(timeout, receiver)
+                .await
+                .map_err(|_| Error::PythonTimeout { timeout })?
+        }
+        None => receiver.await,
+    };
+
+    result.unwrap_or_else(|_| Err(Error::PythonWorkerFailed.into()))
+}
+
+/// The timeout in milliseconds an operator chose or, otherwise, the configured
+/// `python.timeout_ms`
+pub fn configured_timeout(timeout_ms: Option<u64>) -> error::Result<Option<Duration>> {
+    let timeout_ms = match timeout_ms {
+        Some(timeout_ms) => Some(timeout_ms),
+        None => get_config_element::<config::Python>()?.timeout_ms,
+    };
+
+    Ok(timeout_ms.map(Duration::from_millis))
+}
+
+/// Run `job` on the Python worker pool without waiting for it, e.g., to clean up in `Drop`
+pub fn spawn_python(job: impl FnOnce() + Send + 'static) {
+    if !WORKER_POOL.submit(Box::new(job)) {
+        error!("the Python worker pool is gone, a job was skipped");
+    }
+}
+
+/// Where an interruptible job is, the threads are given by their Python thread identifier
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JobState {
+    Waiting,
+    Running(c_ulong),
+    /// The interrupt is about to be raised, see `InterruptOnDrop`
+    Interrupting(c_ulong),
+    Interrupted(c_ulong),
+    Finished,
+}
+
+fn lock(state: &Mutex<JobState>) -> MutexGuard<JobState> {
+    // the state is a plain value, so it is still valid if a thread panicked while holding it
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Run `job` unless it was dropped while waiting, and signal that it is `started`
+fn run_interruptible<T, F>(
+    state: &Mutex<JobState>,
+    started: oneshot::Sender<()>,
+    job: F,
+) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let thread = python_thread_id();
+
+    {
+        let mut state = lock(state);
+        if *state != JobState::Waiting {
+            return Err(Error::PythonWorkerFailed.into());
+        }
+        *state = JobState::Running(thread);
+    }
+
+    let _ = started.send(());
+
+    let result = job();
+
+    let interrupted = std::mem::replace(&mut *lock(state), JobState::Finished);
+
+    if let JobState::Interrupted(thread) = interrupted {
+        // the job may have ended before Python raised the exception, which must not hit the
+        // next job of this thread
+        Python::with_gil(|_| {
+            // SAFETY: the GIL is held and a null exception clears the pending one
+            unsafe { ffi::PyThreadState_SetAsyncExc(thread as _, std::ptr::null_mut()) };
+        });
+    }
+
+    result
+}
+
+/// Raises `KeyboardInterrupt` in the thread of the job when dropped, unless it finished
+struct InterruptOnDrop(Arc<Mutex<JobState>>);
+
+impl Drop for InterruptOnDrop {
+    fn drop(&mut self) {
+        let mut state = lock(&self.0);
+
+        let thread = match *state {
+            JobState::Waiting => {
+                *state = JobState::Finished;
+                return;
+            }
+            JobState::Running(thread) => thread,
+            JobState::Interrupting(_) | JobState::Interrupted(_) | JobState::Finished => return,
+        };
+
+        *state = JobState::Interrupting(thread);
+        drop(state);
+
+        // raising the exception requires the GIL, which the dropping thread, e.g., one of the
+        // async runtime, must not wait for, and the workers may all be busy with runaway jobs
+        let state = self.0.clone();
+        let spawned = std::thread::Builder::new()
+            .name("python-interrupt".to_string())
+            .spawn(move || interrupt(&state, thread));
+
+        if let Err(error) = spawned {
+            error!("cannot interrupt a Python job: {}", error);
+        }
+    }
+}
+
+fn interrupt(state: &Mutex<JobState>, thread: c_ulong) {
+    Python::with_gil(|_| {
+        let mut state = lock(state);
+
+        // the job may have finished while this thread waited for the GIL
+        if *state != JobState::Interrupting(thread) {
+            return;
+        }
+
+        // SAFETY: the GIL is held and the thread cannot finish its job meanwhile, since that
+        // requires the lock on the state
+        unsafe { ffi::PyThreadState_SetAsyncExc(thread as _, ffi::PyExc_KeyboardInterrupt) };
+        *state = JobState::Interrupted(thread);
+    });
+}
+
+/// The identifier of the current thread, as used by `threading.get_ident()`
+fn python_thread_id() -> c_ulong {
+    thread_local! {
+        static THREAD_ID: Cell<Option<c_ulong>> = Cell::new(None);
+    }
+
+    THREAD_ID.with(|id| {
+        let thread = id.get().unwrap_or_else(|| {
+            Python::with_gil(|py| {
+                py.import("threading")
+                    .and_then(|threading| threading.call0("get_ident"))
+                    .and_then(|ident| ident.extract())
+                    .expect("threading.get_ident() returns an integer")
+            })
+        });
+        id.set(Some(thread));
+        thread
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_jobs_on_workers() {
+        let thread_name = run_python(|| Ok(std::thread::current().name().map(String::from)))
+            .await
+            .unwrap();
+
+        assert!(thread_name.unwrap().starts_with("python-worker-"));
+    }
+
+    #[tokio::test]
+    async fn survives_panicking_jobs() {
+        let result = run_python::<(), _>(|| panic!("broken job")).await;
+        assert!(result.is_err());
+
+        assert_eq!(run_python(|| Ok(42)).await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn timeouts_start_when_jobs_run() {
+        let workers = get_config_element::<config::Python>()
+            .unwrap()
+            .worker_threads
+            .max(1);
+
+        // every worker is busy for longer than the timeout
+        for _ in 0..workers {
+            spawn_python(|| std::thread::sleep(Duration::from_millis(300)));
+        }
+
+        let result = run_python_with_timeout(Some(Duration::from_millis(100)), || Ok(42)).await;
+
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn interrupts_jobs_that_time_out() {
+        let (sender, receiver) = mpsc::channel();
+
+        let result = run_python_with_timeout(Some(Duration::from_millis(100)), move || {
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+
+            let error = py.run("while True: pass", None, None).unwrap_err();
+            let _ = sender.send(error.ptype(py).name().to_string());
+
+            Ok(())
+        })
+        .await;
+
+        match result {
+            Err(geoengine_operators::error::Error::InvalidOperatorSpec { reason }) => {
+                assert!(reason.starts_with("PythonTimeoutError"))
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let exception_type = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(exception_type, "KeyboardInterrupt");
+    }
+}